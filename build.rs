@@ -0,0 +1,469 @@
+//! Generates typed bindings for the DevTools protocol from the JSON schema in
+//! `protocol/` when the `protocol` feature is enabled. The output is included
+//! by `src/protocol.rs`.
+
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+use serde_json::Value;
+
+const SCHEMAS: &[&str] = &[
+    "protocol/browser_protocol.json",
+    "protocol/js_protocol.json",
+];
+
+fn main() {
+    for schema in SCHEMAS {
+        println!("cargo:rerun-if-changed={}", schema);
+    }
+    if env::var_os("CARGO_FEATURE_PROTOCOL").is_none() {
+        return;
+    }
+
+    let mut domains = Vec::new();
+    for schema in SCHEMAS {
+        let content = fs::read_to_string(schema).expect("Failed to read schema");
+        let mut value: Value = serde_json::from_str(&content).expect("Invalid schema");
+        if let Value::Array(ref mut items) = value["domains"] {
+            domains.append(items);
+        }
+    }
+
+    let code = Generator::new(&domains).generate();
+    let out_dir = env::var("OUT_DIR").unwrap();
+    fs::write(Path::new(&out_dir).join("protocol.rs"), code).expect("Failed to write bindings");
+}
+
+/// Generates one module per domain.
+struct Generator<'a> {
+    domains: &'a [Value],
+    /// Object types by `Domain.Type`.
+    objects: HashMap<String, &'a Value>,
+    /// Properties that need a `Box` to break a cycle, as `Domain.Type.name`.
+    boxed: HashSet<String>,
+}
+
+/// Items generated for the domain at hand.
+#[derive(Default)]
+struct Module {
+    code: String,
+    names: HashSet<String>,
+}
+
+impl<'a> Generator<'a> {
+    fn new(domains: &'a [Value]) -> Self {
+        let mut objects = HashMap::new();
+        for domain in domains {
+            let domain_name = str_of(domain, "domain");
+            for ty in array_of(domain, "types") {
+                if ty.get("properties").is_some() {
+                    objects.insert(format!("{}.{}", domain_name, str_of(ty, "id")), ty);
+                }
+            }
+        }
+        let mut generator = Generator {
+            domains,
+            objects,
+            boxed: HashSet::new(),
+        };
+        generator.find_cycles();
+        generator
+    }
+
+    /// Marks properties that refer back to their own type, directly or
+    /// through other fields. Arrays don't count since `Vec` is already
+    /// indirect.
+    fn find_cycles(&mut self) {
+        let mut edges: HashMap<&str, Vec<(String, String)>> = HashMap::new();
+        for (name, ty) in &self.objects {
+            let domain = name.split('.').next().unwrap();
+            for property in array_of(ty, "properties") {
+                if let Some(target) = property.get("$ref").and_then(Value::as_str) {
+                    let target = qualify(domain, target);
+                    if self.objects.contains_key(&target) {
+                        let field = format!("{}.{}", name, str_of(property, "name"));
+                        edges.entry(name).or_default().push((target, field));
+                    }
+                }
+            }
+        }
+
+        for (source, fields) in &edges {
+            for (target, field) in fields {
+                let mut seen = HashSet::new();
+                let mut stack = vec![target.as_str()];
+                while let Some(node) = stack.pop() {
+                    if node == *source {
+                        self.boxed.insert(field.clone());
+                        break;
+                    }
+                    if !seen.insert(node) {
+                        continue;
+                    }
+                    if let Some(next) = edges.get(node) {
+                        stack.extend(next.iter().map(|(target, _)| target.as_str()));
+                    }
+                }
+            }
+        }
+    }
+
+    fn generate(&self) -> String {
+        let mut code = String::new();
+        code.push_str("// Generated by build.rs from the protocol schema. Do not edit.\n");
+        for domain in self.domains {
+            code.push('\n');
+            code.push_str(&self.generate_domain(domain));
+        }
+        code
+    }
+
+    fn generate_domain(&self, domain: &Value) -> String {
+        let domain_name = str_of(domain, "domain");
+        let mut module = Module::default();
+
+        for ty in array_of(domain, "types") {
+            self.generate_type(&mut module, domain_name, ty);
+        }
+        for command in array_of(domain, "commands") {
+            self.generate_command(&mut module, domain_name, command);
+        }
+        for event in array_of(domain, "events") {
+            let name = format!("{}Event", pascal_case(str_of(event, "name")));
+            let method = format!("{}.{}", domain_name, str_of(event, "name"));
+            let properties = array_of(event, "parameters");
+            self.generate_struct(&mut module, domain_name, &name, event, properties, None);
+            writeln!(
+                module.code,
+                "impl super::Event for {} {{\n    const NAME: &'static str = \"{}\";\n}}\n",
+                name, method
+            )
+            .unwrap();
+        }
+
+        let mut code = String::new();
+        write_docs(&mut code, "", domain);
+        code.push_str("#[allow(clippy::all, rustdoc::all)]\n");
+        writeln!(code, "pub mod {} {{", snake_case(domain_name)).unwrap();
+        code.push_str("    use serde::{Deserialize, Serialize};\n\n");
+        for line in module.code.trim_end().lines() {
+            if line.is_empty() {
+                code.push('\n');
+            } else {
+                writeln!(code, "    {}", line).unwrap();
+            }
+        }
+        code.push_str("}\n");
+        code
+    }
+
+    fn generate_type(&self, module: &mut Module, domain: &str, ty: &Value) {
+        let name = pascal_case(str_of(ty, "id"));
+        if ty.get("properties").is_some() {
+            let properties = array_of(ty, "properties");
+            self.generate_struct(module, domain, &name, ty, properties, None);
+        } else if let Some(variants) = ty.get("enum") {
+            self.generate_enum(module, &name, ty, variants);
+        } else {
+            let target = self.rust_type(module, &name, ty);
+            claim_name(module, &name);
+            write_docs(&mut module.code, "", ty);
+            writeln!(module.code, "pub type {} = {};\n", name, target).unwrap();
+        }
+    }
+
+    fn generate_command(&self, module: &mut Module, domain: &str, command: &Value) {
+        let base = pascal_case(str_of(command, "name"));
+        let params = format!("{}Params", base);
+        let returns = format!("{}Returns", base);
+        let method = format!("{}.{}", domain, str_of(command, "name"));
+
+        let properties = array_of(command, "parameters");
+        self.generate_struct(module, domain, &params, command, properties, None);
+        let properties = array_of(command, "returns");
+        let docs = format!("Return value of `{}`.", method);
+        self.generate_struct(module, domain, &returns, command, properties, Some(&docs));
+        writeln!(
+            module.code,
+            "impl super::Command for {} {{\n    const NAME: &'static str = \"{}\";\n    type Returns = {};\n}}\n",
+            params, method, returns
+        )
+        .unwrap();
+    }
+
+    fn generate_struct(
+        &self,
+        module: &mut Module,
+        domain: &str,
+        name: &str,
+        item: &Value,
+        properties: &[Value],
+        docs: Option<&str>,
+    ) {
+        claim_name(module, name);
+        let mut fields = String::new();
+        let mut all_optional = true;
+        for property in properties {
+            let property_name = str_of(property, "name");
+            let enum_name = format!(
+                "{}{}",
+                name.trim_end_matches("Params"),
+                pascal_case(property_name)
+            );
+            let mut ty = self.rust_type(module, &enum_name, property);
+            if self
+                .boxed
+                .contains(&format!("{}.{}.{}", domain, name, property_name))
+            {
+                ty = format!("Box<{}>", ty);
+            }
+
+            write_docs(&mut fields, "    ", property);
+            let field = field_name(property_name);
+            if serde_camel_case(field.trim_start_matches("r#")) != property_name {
+                writeln!(fields, "    #[serde(rename = \"{}\")]", property_name).unwrap();
+            }
+            if property.get("optional").and_then(Value::as_bool) == Some(true) {
+                fields
+                    .push_str("    #[serde(default, skip_serializing_if = \"Option::is_none\")]\n");
+                writeln!(fields, "    pub {}: Option<{}>,", field, ty).unwrap();
+            } else {
+                all_optional = false;
+                writeln!(fields, "    pub {}: {},", field, ty).unwrap();
+            }
+        }
+
+        match docs {
+            Some(docs) => writeln!(module.code, "/// {}", docs).unwrap(),
+            None => write_docs(&mut module.code, "", item),
+        }
+        let default = if all_optional { ", Default" } else { "" };
+        writeln!(
+            module.code,
+            "#[derive(Debug, Clone, PartialEq{}, Serialize, Deserialize)]",
+            default
+        )
+        .unwrap();
+        module
+            .code
+            .push_str("#[serde(rename_all = \"camelCase\")]\n");
+        if fields.is_empty() {
+            writeln!(module.code, "pub struct {} {{}}\n", name).unwrap();
+        } else {
+            writeln!(module.code, "pub struct {} {{\n{}}}\n", name, fields).unwrap();
+        }
+    }
+
+    fn generate_enum(&self, module: &mut Module, name: &str, item: &Value, variants: &Value) {
+        claim_name(module, name);
+        write_docs(&mut module.code, "", item);
+        module.code.push_str(
+            "#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]\n",
+        );
+        writeln!(module.code, "pub enum {} {{", name).unwrap();
+        let mut seen = HashSet::new();
+        for value in variants.as_array().unwrap() {
+            let value = value.as_str().unwrap();
+            let mut variant = variant_name(value);
+            while !seen.insert(variant.clone()) {
+                variant.push('_');
+            }
+            writeln!(module.code, "    #[serde(rename = \"{}\")]", value).unwrap();
+            writeln!(module.code, "    {},", variant).unwrap();
+        }
+        // Browsers newer than the bundled protocol can send values we don't
+        // know about; those must not fail the whole message.
+        let mut unknown = "Unknown".to_owned();
+        while !seen.insert(unknown.clone()) {
+            unknown.push('_');
+        }
+        module
+            .code
+            .push_str("    /// A value added after the bundled protocol version.\n");
+        module
+            .code
+            .push_str("    #[serde(other, skip_serializing)]\n");
+        writeln!(module.code, "    {},", unknown).unwrap();
+        module.code.push_str("}\n\n");
+    }
+
+    /// Returns the Rust type of a property or type. Inline enums are added to
+    /// `module` as `enum_name`.
+    fn rust_type(&self, module: &mut Module, enum_name: &str, item: &Value) -> String {
+        if let Some(target) = item.get("$ref").and_then(Value::as_str) {
+            return match target.find('.') {
+                Some(dot) => format!(
+                    "super::{}::{}",
+                    snake_case(&target[..dot]),
+                    pascal_case(&target[dot + 1..])
+                ),
+                None => pascal_case(target),
+            };
+        }
+        match str_of(item, "type") {
+            "string" if item.get("enum").is_some() => {
+                self.generate_enum(module, enum_name, item, &item["enum"]);
+                enum_name.to_owned()
+            }
+            "string" | "binary" => "String".to_owned(),
+            "integer" => "i64".to_owned(),
+            "number" => "f64".to_owned(),
+            "boolean" => "bool".to_owned(),
+            "array" => {
+                let item_enum_name = format!("{}Item", enum_name);
+                let items = &item["items"];
+                format!("Vec<{}>", self.rust_type(module, &item_enum_name, items))
+            }
+            "any" | "object" => "serde_json::Value".to_owned(),
+            other => panic!("Unknown type: {}", other),
+        }
+    }
+}
+
+fn claim_name(module: &mut Module, name: &str) {
+    if !module.names.insert(name.to_owned()) {
+        panic!("Duplicate item: {}", name);
+    }
+}
+
+fn qualify(domain: &str, target: &str) -> String {
+    if target.contains('.') {
+        target.to_owned()
+    } else {
+        format!("{}.{}", domain, target)
+    }
+}
+
+fn str_of<'v>(value: &'v Value, key: &str) -> &'v str {
+    value.get(key).and_then(Value::as_str).unwrap_or("")
+}
+
+fn array_of<'v>(value: &'v Value, key: &str) -> &'v [Value] {
+    value
+        .get(key)
+        .and_then(Value::as_array)
+        .map(Vec::as_slice)
+        .unwrap_or(&[])
+}
+
+fn write_docs(code: &mut String, indent: &str, item: &Value) {
+    let mut lines: Vec<String> = str_of(item, "description")
+        .lines()
+        .map(|line| line.trim().to_owned())
+        .collect();
+    let flag = |key| item.get(key).and_then(Value::as_bool) == Some(true);
+    let mut notes = Vec::new();
+    if flag("experimental") {
+        notes.push("**Experimental.**");
+    }
+    if flag("deprecated") {
+        notes.push("**Deprecated.**");
+    }
+    if !notes.is_empty() {
+        if !lines.is_empty() {
+            lines.push(String::new());
+        }
+        lines.push(notes.join(" "));
+    }
+    for line in lines {
+        if line.is_empty() {
+            writeln!(code, "{}///", indent).unwrap();
+        } else {
+            writeln!(code, "{}/// {}", indent, line).unwrap();
+        }
+    }
+}
+
+/// `DOMDebugger` -> `dom_debugger`.
+fn snake_case(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    let mut out = String::new();
+    for (i, &c) in chars.iter().enumerate() {
+        if c.is_ascii_uppercase() && i > 0 {
+            let prev = chars[i - 1];
+            let next_lower = chars.get(i + 1).is_some_and(|c| c.is_ascii_lowercase());
+            if prev.is_ascii_lowercase()
+                || prev.is_ascii_digit()
+                || (prev.is_ascii_uppercase() && next_lower)
+            {
+                out.push('_');
+            }
+        }
+        out.push(c.to_ascii_lowercase());
+    }
+    out
+}
+
+/// `navigate` -> `Navigate`.
+fn pascal_case(name: &str) -> String {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) => first.to_ascii_uppercase().to_string() + chars.as_str(),
+        None => String::new(),
+    }
+}
+
+/// `console-api` -> `ConsoleApi`. All-caps words are capitalized so that
+/// `GET` becomes `Get`.
+fn variant_name(value: &str) -> String {
+    let mut out = String::new();
+    for word in value.split(|c: char| !c.is_ascii_alphanumeric()) {
+        if word.is_empty() {
+            continue;
+        }
+        if word
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit())
+        {
+            out.push_str(&word[..1]);
+            out.push_str(&word[1..].to_ascii_lowercase());
+        } else {
+            out.push_str(&pascal_case(word));
+        }
+    }
+    if out.starts_with(|c: char| c.is_ascii_digit()) {
+        out.insert(0, 'V');
+    }
+    if out == "Self" {
+        out.push('_');
+    }
+    out
+}
+
+fn field_name(name: &str) -> String {
+    const KEYWORDS: &[&str] = &[
+        "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "do",
+        "dyn", "else", "enum", "extern", "false", "final", "fn", "for", "if", "impl", "in", "let",
+        "loop", "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref", "return",
+        "static", "struct", "trait", "true", "try", "type", "typeof", "unsafe", "unsized", "use",
+        "virtual", "where", "while", "yield",
+    ];
+    let name = snake_case(name);
+    if KEYWORDS.contains(&name.as_str()) {
+        format!("r#{}", name)
+    } else if name == "self" || name == "super" || name == "crate" {
+        format!("{}_", name)
+    } else {
+        name
+    }
+}
+
+/// Mirrors `#[serde(rename_all = "camelCase")]` for a snake case field.
+fn serde_camel_case(field: &str) -> String {
+    let mut out = String::new();
+    let mut capitalize = false;
+    for c in field.chars() {
+        if c == '_' {
+            capitalize = true;
+        } else if capitalize {
+            out.push(c.to_ascii_uppercase());
+            capitalize = false;
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
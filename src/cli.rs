@@ -1,28 +1,45 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
+
 use rustyline::error::ReadlineError;
-use rustyline::Editor;
+use rustyline::{CompletionType, Config, Editor};
+use smol::prelude::*;
+use smol::{Timer, Unblock};
 
-use crate::endpoints::Endpoints;
-use crate::websocket_target::{MethodCall, WebSocketTarget};
-use crate::{Error, Opt};
+use cdp_rs::discovery::{self, ACTIVE_PORT_FILE};
+use cdp_rs::schema::{Protocol, SchemaCache, Type, TypeKind, ValidationError};
+use cdp_rs::websocket;
+use cdp_rs::{
+    Browser, Closed, Endpoints, Error, Event, EventFilter, EventStream, LaunchOptions, MethodCall,
+    ParseError, Predicate, Session, TlsConfig, WebSocketTarget,
+};
+use serde_json::Value;
 
-pub(crate) async fn run_repl(opt: Opt) -> Result<(), Error> {
-    let endpoints = Endpoints::new(&opt.host, opt.port).await?;
+use crate::completion::ReplHelper;
+use crate::{Opt, Subcommand};
 
-    // Tentative: Create a new tab if not exists, then set it as the initial target.
-    const NEWTAB_URL: &'static str = "chrome://newtab/";
-    let targets = endpoints.target_list().await?;
-    let newtab = targets.into_iter().find(|t| t.url == NEWTAB_URL);
-    let target_url = match newtab {
-        Some(newtab) => newtab.websocket_debugger_url,
-        None => {
-            let newtab = endpoints.open_new_tab(NEWTAB_URL).await?;
-            newtab.websocket_debugger_url
-        }
-    };
-    let target_url = url::Url::parse(&target_url)?;
-    let mut target = WebSocketTarget::connect(target_url).await?;
+/// Exit code when a command fails.
+const EXIT_FAILURE: i32 = 1;
+/// Exit code when a method call can't be parsed or is invalid.
+const EXIT_INVALID: i32 = 2;
 
-    let mut rl = Editor::<()>::new();
+pub(crate) async fn run_repl(opt: Opt) -> Result<(), Error> {
+    let mut browser = launch_browser(&opt).await?;
+    let endpoints = endpoints(&opt, browser.as_ref()).await?;
+    let mut conn = connect_initial_target(endpoints.as_ref(), browser.as_mut(), &opt).await?;
+    conn.report_disconnect();
+    let protocol = fetch_protocol(endpoints.as_ref()).await.map(Rc::new);
+    let mut variables = HashMap::new();
+
+    let config = Config::builder()
+        .completion_type(CompletionType::List)
+        .build();
+    let mut rl = Editor::<ReplHelper>::with_config(config);
+    rl.set_helper(Some(ReplHelper::new(protocol.clone())));
 
     let _ = rl.load_history("history.txt"); // Ignore error
     loop {
@@ -30,9 +47,19 @@ pub(crate) async fn run_repl(opt: Opt) -> Result<(), Error> {
         match readline {
             Ok(line) => {
                 rl.add_history_entry(line.as_str());
-                match parse_command_line(&line) {
-                    Some(command) => execute_command(command, &endpoints, &mut target).await?,
-                    None => (),
+                if let Some(command) = parse_command_line(&line, &variables) {
+                    let res = execute_command(
+                        command,
+                        &opt,
+                        endpoints.as_ref(),
+                        protocol.as_deref(),
+                        &mut conn,
+                        &mut variables,
+                    )
+                    .await;
+                    if let Err(err) = res {
+                        print_error(&err);
+                    }
                 }
             }
             Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => {
@@ -44,23 +71,496 @@ pub(crate) async fn run_repl(opt: Opt) -> Result<(), Error> {
             }
         }
     }
-    rl.save_history("history.txt")?;
+    if let Err(err) = rl.save_history("history.txt") {
+        println!("Failed to save history: {}", err);
+    }
+    conn.target.close().await?;
+    Ok(())
+}
+
+/// Runs a single command and prints its result as JSON. Returns the exit
+/// code.
+pub(crate) async fn run_command(command: Subcommand, opt: Opt) -> Result<i32, Error> {
+    if let Subcommand::Discover { ports } = command {
+        let found = discovery::discover(&opt.host, ports).await;
+        print_json(&serde_json::to_value(found)?)?;
+        return Ok(0);
+    }
+    let mut browser = launch_browser(&opt).await?;
+    let endpoints = endpoints(&opt, browser.as_ref()).await?;
+    match command {
+        Subcommand::Call { method: line } => {
+            let method = match MethodCall::parse(&line) {
+                Ok(method) => method,
+                Err(err) => {
+                    print_syntax_error(&line, &err);
+                    return Ok(EXIT_INVALID);
+                }
+            };
+            if !opt.no_validate {
+                if let Some(protocol) = fetch_protocol(endpoints.as_ref()).await {
+                    if let Err(errors) = protocol.validate(&method.method(), method.params()) {
+                        print_validation_errors(&errors);
+                        return Ok(EXIT_INVALID);
+                    }
+                }
+            }
+            let conn = connect_initial_target(endpoints.as_ref(), browser.as_mut(), &opt).await?;
+            let result = match conn.session {
+                Some(ref session) => session.call_method(&method).await,
+                None => conn.target.call_method(&method).await,
+            };
+            print_json(&close_after(&conn.target, result).await?)?;
+        }
+        Subcommand::List | Subcommand::Version | Subcommand::New { .. } => {
+            let value = match (endpoints, browser.as_mut()) {
+                (Some(endpoints), _) => match command {
+                    Subcommand::List => serde_json::to_value(endpoints.target_list().await?)?,
+                    Subcommand::Version => serde_json::to_value(endpoints.version().await?)?,
+                    Subcommand::New { url } => {
+                        serde_json::to_value(endpoints.open_new_tab(url).await?)?
+                    }
+                    _ => unreachable!(),
+                },
+                (None, Some(browser)) => {
+                    let target = connect_pipe(browser, &opt)?;
+                    let (method, params) = match command {
+                        Subcommand::List => ("Target.getTargets", serde_json::json!({})),
+                        Subcommand::Version => ("Browser.getVersion", serde_json::json!({})),
+                        Subcommand::New { url } => {
+                            ("Target.createTarget", serde_json::json!({ "url": url }))
+                        }
+                        _ => unreachable!(),
+                    };
+                    let result = target.call_method(&MethodCall::new(method, params)).await;
+                    close_after(&target, result).await?
+                }
+                (None, None) => unreachable!("endpoints are only missing with --pipe"),
+            };
+            print_json(&value)?;
+        }
+        Subcommand::Run { script } => {
+            return run_script(&script, &opt, endpoints.as_ref(), browser.as_mut()).await
+        }
+        Subcommand::Discover { .. } => unreachable!(),
+    }
+    Ok(0)
+}
+
+/// Runs the commands in a script one after another, like in the REPL, and
+/// stops at the first one that fails. Returns the exit code.
+async fn run_script(
+    path: &Path,
+    opt: &Opt,
+    endpoints: Option<&Endpoints>,
+    browser: Option<&mut Browser>,
+) -> Result<i32, Error> {
+    let file = path.to_owned();
+    let content = smol::unblock(move || fs::read_to_string(file)).await?;
+    let mut conn = connect_initial_target(endpoints, browser, opt).await?;
+    let protocol = if opt.no_validate {
+        None
+    } else {
+        fetch_protocol(endpoints).await
+    };
+    let code = run_statements(path, &content, opt, endpoints, protocol.as_ref(), &mut conn).await;
+    close_after(&conn.target, code).await
+}
+
+/// Runs the statements of a script on `conn`. Returns the exit code.
+async fn run_statements(
+    path: &Path,
+    content: &str,
+    opt: &Opt,
+    endpoints: Option<&Endpoints>,
+    protocol: Option<&Protocol>,
+    conn: &mut Connection,
+) -> Result<i32, Error> {
+    let mut variables = HashMap::new();
+    let mut lines = content.lines().enumerate();
+    while let Some((i, line)) = lines.next() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let line_number = i + 1;
+        let mut statement = line.to_owned();
+        while is_incomplete(&statement) {
+            match lines.next() {
+                Some((_, line)) => {
+                    statement.push('\n');
+                    statement.push_str(line);
+                }
+                None => break,
+            }
+        }
+
+        let command = match parse_command_line(&statement, &variables) {
+            Some(command) => command,
+            None => continue,
+        };
+        if let Command::SyntaxError(text, err) = command {
+            let err_line = line_number + err.line - 1;
+            eprintln!(
+                "{}:{}: Syntax error: {}",
+                path.display(),
+                err_line,
+                err.message
+            );
+            print_error_position(&text, &err);
+            return Ok(EXIT_INVALID);
+        }
+        let res = execute_command(command, opt, endpoints, protocol, conn, &mut variables).await;
+        let code = match res {
+            Ok(Outcome::Done) => continue,
+            Ok(Outcome::Invalid) => EXIT_INVALID,
+            Err(err) => {
+                print_error(&err);
+                EXIT_FAILURE
+            }
+        };
+        eprintln!("Stopped at {}:{}", path.display(), line_number);
+        return Ok(code);
+    }
+    Ok(0)
+}
+
+/// Closes `target`, then returns `result`, so that the connection is closed
+/// however the commands using it ended. An error in `result` takes
+/// precedence over one closing the connection.
+async fn close_after<T>(target: &WebSocketTarget, result: Result<T, Error>) -> Result<T, Error> {
+    let closed = target.close().await;
+    let value = result?;
+    closed?;
+    Ok(value)
+}
+
+/// Starts a browser with `--launch`. It is killed when dropped.
+async fn launch_browser(opt: &Opt) -> Result<Option<Browser>, Error> {
+    if !opt.launch {
+        return Ok(None);
+    }
+    let options = LaunchOptions {
+        executable: opt.chrome.clone(),
+        headless: opt.headless,
+        user_data_dir: opt.user_data_dir.clone(),
+        args: opt.chrome_args.clone(),
+        pipe: opt.pipe,
+        ..LaunchOptions::default()
+    };
+    Ok(Some(Browser::launch(&options).await?))
+}
+
+/// The endpoints of the launched browser, of the one using
+/// `--user-data-dir`, or of `--host` and `--port`. A browser launched with
+/// `--pipe` has none.
+async fn endpoints(opt: &Opt, browser: Option<&Browser>) -> Result<Option<Endpoints>, Error> {
+    if let Some(browser) = browser {
+        return Ok(browser.endpoints());
+    }
+    if let Some(ref dir) = opt.user_data_dir {
+        return Endpoints::from_user_data_dir(dir)
+            .await
+            .map(Some)
+            .map_err(|err| match err {
+                Error::Io(err) => Error::Io(io::Error::new(
+                    err.kind(),
+                    format!(
+                        "Can't read {}: {}",
+                        dir.join(ACTIVE_PORT_FILE).display(),
+                        err
+                    ),
+                )),
+                err => err,
+            });
+    }
+    let tls = if opt.https {
+        Some(tls_config(opt))
+    } else {
+        None
+    };
+    Ok(Some(Endpoints::new(&opt.host, opt.port, tls)))
+}
+
+/// Connects to a new tab, over the browser connection with `--browser`. With
+/// `--pipe`, attaches to a page over the pipes instead.
+async fn connect_initial_target(
+    endpoints: Option<&Endpoints>,
+    browser: Option<&mut Browser>,
+    opt: &Opt,
+) -> Result<Connection, Error> {
+    let endpoints = match (endpoints, browser) {
+        (Some(endpoints), _) => endpoints,
+        (None, Some(browser)) => return attach_to_page(connect_pipe(browser, opt)?).await,
+        (None, None) => unreachable!("endpoints are only missing with --pipe"),
+    };
+    // Tentative: Create a new tab if not exists, then set it as the initial target.
+    const NEWTAB_URL: &str = "chrome://newtab/";
+    let targets = endpoints.target_list().await?;
+    let newtab = targets.into_iter().find(|t| t.url == NEWTAB_URL);
+    let newtab = match newtab {
+        Some(newtab) => newtab,
+        None => endpoints.open_new_tab(NEWTAB_URL).await?,
+    };
+    if opt.browser {
+        let version = endpoints.version().await?;
+        let url = target_url(&version.websocket_debugger_url, opt)?;
+        let mut conn = Connection::new(connect_target(url, opt).await?);
+        conn.session = Some(conn.target.attach(&newtab.id).await?);
+        Ok(conn)
+    } else {
+        let url = target_url(&newtab.websocket_debugger_url, opt)?;
+        Ok(Connection::new(connect_target(url, opt).await?))
+    }
+}
+
+/// Attaches to a page of the browser, creating one if there is none.
+async fn attach_to_page(target: WebSocketTarget) -> Result<Connection, Error> {
+    let method = MethodCall::new("Target.getTargets", serde_json::json!({}));
+    let targets = target.call_method(&method).await?;
+    let page = targets["targetInfos"]
+        .as_array()
+        .and_then(|infos| infos.iter().find(|info| info["type"] == "page"))
+        .and_then(|info| info["targetId"].as_str())
+        .map(str::to_owned);
+    let target_id = match page {
+        Some(target_id) => target_id,
+        None => {
+            let params = serde_json::json!({ "url": "about:blank" });
+            let method = MethodCall::new("Target.createTarget", params);
+            let created = target.call_method(&method).await?;
+            let target_id = created["targetId"].as_str();
+            let target_id = target_id
+                .ok_or_else(|| Error::InvalidResponse("No targetId in reply".to_owned()))?;
+            target_id.to_owned()
+        }
+    };
+    let mut conn = Connection::new(target);
+    conn.session = Some(conn.target.attach(&target_id).await?);
+    Ok(conn)
+}
+
+/// How many events `wait` can look back on. Older ones are dropped.
+const MAX_BUFFERED_EVENTS: usize = 1000;
+
+/// The target commands are sent to, and the latest events received since the
+/// current command started, for `wait`.
+struct Connection {
+    target: WebSocketTarget,
+    session: Option<Session>,
+    events: async_channel::Receiver<Event>,
+    /// Moves events into `events`; cancelled when the connection is dropped.
+    _forward_events: smol::Task<()>,
+    /// Whether losing the connection is reported, as in the REPL.
+    reports_disconnect: bool,
+}
+
+impl Connection {
+    fn new(target: WebSocketTarget) -> Self {
+        let mut stream = target.subscribe_all(EventFilter::All);
+        let (sender, events) = async_channel::bounded(MAX_BUFFERED_EVENTS);
+        let oldest = events.clone();
+        let forward_events = smol::Task::spawn(async move {
+            while let Some(mut event) = stream.next().await {
+                while let Err(async_channel::TrySendError::Full(rejected)) = sender.try_send(event)
+                {
+                    let _ = oldest.try_recv();
+                    event = rejected;
+                }
+            }
+        });
+        Connection {
+            target,
+            session: None,
+            events,
+            _forward_events: forward_events,
+            reports_disconnect: false,
+        }
+    }
+
+    /// Prints a message if the connection is lost. A connection closed on
+    /// purpose isn't reported.
+    fn report_disconnect(&mut self) {
+        let closed = self.target.closed();
+        smol::Task::spawn(async move {
+            if let closed @ Closed::Disconnected(_) = closed.await {
+                eprintln!("{}", closed);
+            }
+        })
+        .detach();
+        self.reports_disconnect = true;
+    }
+
+    /// Drops the events received so far.
+    fn clear_events(&mut self) {
+        while self.events.try_recv().is_ok() {}
+    }
+
+    /// Waits for an event that matches, of the current session or of the
+    /// browser itself.
+    async fn wait_event(
+        &mut self,
+        method: &str,
+        predicate: Option<&Predicate>,
+    ) -> Result<Event, Error> {
+        let session_id = self.session.as_ref().map(|session| session.id().to_owned());
+        while let Ok(event) = self.events.recv().await {
+            if event.method == method
+                && (event.session_id.is_none() || event.session_id == session_id)
+                && predicate.is_none_or(|predicate| predicate.matches(&event.params))
+            {
+                return Ok(event);
+            }
+        }
+        Err(Error::Disconnected("Connection closed".to_owned()))
+    }
+}
+
+/// Gets the protocol schema, from the cache if possible. Completion, help and
+/// validation are unavailable without it, so failures are only reported. It
+/// can't be fetched without endpoints.
+async fn fetch_protocol(endpoints: Option<&Endpoints>) -> Option<Protocol> {
+    let endpoints = endpoints?;
+    let protocol = match SchemaCache::default_dir() {
+        Some(dir) => endpoints.protocol_cached(&SchemaCache::new(dir)).await,
+        None => endpoints.protocol().await,
+    };
+    match protocol {
+        Ok(protocol) => Some(protocol),
+        Err(err) => {
+            eprintln!("Failed to get the protocol schema: {}", err);
+            None
+        }
+    }
+}
+
+fn target_url(url: &str, opt: &Opt) -> Result<url::Url, Error> {
+    let mut url = url::Url::parse(url)?;
+    if opt.https && url.scheme() == "ws" {
+        // The browser doesn't know about a TLS-terminating proxy in front of
+        // it.
+        let _ = url.set_scheme("wss");
+    }
+    Ok(url)
+}
+
+fn tls_config(opt: &Opt) -> TlsConfig {
+    TlsConfig {
+        ca_file: opt.ca_file.clone(),
+        insecure: opt.insecure,
+    }
+}
+
+async fn connect_target(url: url::Url, opt: &Opt) -> Result<WebSocketTarget, Error> {
+    let target = WebSocketTarget::connect(url, &websocket_config(opt)).await?;
+    Ok(set_up_target(target, opt))
+}
+
+/// Connects to a browser launched with `--pipe`.
+fn connect_pipe(browser: &mut Browser, opt: &Opt) -> Result<WebSocketTarget, Error> {
+    match browser.take_pipe() {
+        Some((sender, receiver)) => {
+            let target = WebSocketTarget::from_pipe(sender, receiver);
+            Ok(set_up_target(target, opt))
+        }
+        None => Err(Error::Disconnected(
+            "The pipes are already in use".to_owned(),
+        )),
+    }
+}
+
+fn websocket_config(opt: &Opt) -> websocket::Config {
+    let mut config = websocket::Config {
+        max_frame_size: opt.max_frame_size,
+        keepalive_interval: opt.keepalive_interval.map(Duration::from_secs),
+        tls: tls_config(opt),
+        ..websocket::Config::default()
+    };
+    if opt.no_compression {
+        config.deflate = None;
+    }
+    if opt.max_message_size.is_some() {
+        config.max_message_size = opt.max_message_size;
+    }
+    config
+}
+
+/// Applies `--timeout` and writes `--events-log`.
+fn set_up_target(mut target: WebSocketTarget, opt: &Opt) -> WebSocketTarget {
+    if opt.timeout > 0 {
+        target.set_default_timeout(Some(Duration::from_secs(opt.timeout)));
+    }
+
+    if let Some(ref path) = opt.events_log {
+        let events = target.subscribe_all(opt.events_filter.clone());
+        let path = path.clone();
+        smol::Task::spawn(async move {
+            if let Err(err) = log_events(events, path).await {
+                println!("Failed to write events: {}", err);
+            }
+        })
+        .detach();
+    }
+    target
+}
+
+async fn log_events(mut events: EventStream, path: PathBuf) -> Result<(), Error> {
+    let mut file = Unblock::new(smol::unblock(move || File::create(path)).await?);
+    while let Some(event) = events.next().await {
+        let res = serde_json::to_string_pretty(&event_json(event))?;
+        file.write_all(res.as_bytes()).await?;
+        file.write_all(b"\n").await?;
+        file.flush().await?;
+        file.with_mut(|file| file.sync_all()).await?;
+    }
     Ok(())
 }
 
+fn event_json(event: Event) -> Value {
+    let mut value = serde_json::json!({
+        "method": event.method,
+        "params": event.params,
+    });
+    if let Some(session_id) = event.session_id {
+        value["sessionId"] = session_id.into();
+    }
+    value
+}
+
 enum Command {
     Version,
     List,
     NewTab(String),
     ConnectTarget(String),
+    Attach(String),
+    Detach,
     ActivateTarget(String),
     CloseTarget(String),
+    Help(Option<String>),
     MethodCall(MethodCall),
+    Let(String, MethodCall),
+    Wait(Wait),
+    SyntaxError(String, ParseError),
     Unknown(String),
 }
 
-fn parse_command_line(line: &str) -> Option<Command> {
-    if line.len() == 0 {
+/// `wait Domain.event [timeout] [predicate]`.
+struct Wait {
+    event: String,
+    timeout: Option<Duration>,
+    predicate: Option<Predicate>,
+}
+
+/// Whether a command could run. Problems with commands that couldn't have
+/// already been reported.
+enum Outcome {
+    Done,
+    Invalid,
+}
+
+fn parse_command_line(line: &str, variables: &HashMap<String, Value>) -> Option<Command> {
+    let line = line.trim();
+    if line.is_empty() {
         return None;
     }
 
@@ -73,71 +573,572 @@ fn parse_command_line(line: &str) -> Option<Command> {
     }
 
     const NEW_TAB_COMMAND: &str = "newtab ";
-    if line.starts_with(NEW_TAB_COMMAND) {
-        let url = line[NEW_TAB_COMMAND.len()..].to_string();
+    if let Some(url) = line.strip_prefix(NEW_TAB_COMMAND) {
+        let url = url.to_string();
         return Some(Command::NewTab(url));
     }
 
     const CONNECT_TARGET_COMMAND: &str = "connect ";
-    if line.starts_with(CONNECT_TARGET_COMMAND) {
-        let url = line[CONNECT_TARGET_COMMAND.len()..].to_string();
+    if let Some(url) = line.strip_prefix(CONNECT_TARGET_COMMAND) {
+        let url = url.to_string();
         return Some(Command::ConnectTarget(url));
     }
 
+    const ATTACH_COMMAND: &str = "attach ";
+    if let Some(target_id) = line.strip_prefix(ATTACH_COMMAND) {
+        let target_id = target_id.to_string();
+        return Some(Command::Attach(target_id));
+    }
+
+    if line == "detach" {
+        return Some(Command::Detach);
+    }
+
     const ACTIVATE_TARGET_COMMAND: &str = "activate ";
-    if line.starts_with(ACTIVATE_TARGET_COMMAND) {
-        let target_id = line[ACTIVATE_TARGET_COMMAND.len()..].to_string();
+    if let Some(target_id) = line.strip_prefix(ACTIVATE_TARGET_COMMAND) {
+        let target_id = target_id.to_string();
         return Some(Command::ActivateTarget(target_id));
     }
 
     const CLOSE_TARGET_COMMAND: &str = "close ";
-    if line.starts_with(CLOSE_TARGET_COMMAND) {
-        let target_id = line[CLOSE_TARGET_COMMAND.len()..].to_string();
+    if let Some(target_id) = line.strip_prefix(CLOSE_TARGET_COMMAND) {
+        let target_id = target_id.to_string();
         return Some(Command::CloseTarget(target_id));
     }
 
-    if let Some(msg) = MethodCall::from_str(line) {
-        return Some(Command::MethodCall(msg));
+    if line == "help" {
+        return Some(Command::Help(None));
+    }
+
+    const HELP_COMMAND: &str = "help ";
+    if let Some(topic) = line.strip_prefix(HELP_COMMAND) {
+        let topic = topic.trim();
+        let topic = if topic.is_empty() {
+            None
+        } else {
+            Some(topic.to_string())
+        };
+        return Some(Command::Help(topic));
+    }
+
+    const WAIT_COMMAND: &str = "wait ";
+    if let Some(args) = line.strip_prefix(WAIT_COMMAND) {
+        return Some(
+            parse_wait(args, variables).unwrap_or_else(|| Command::Unknown(line.to_owned())),
+        );
+    }
+
+    if let Some((name, call)) = split_let(line) {
+        let is_name = !name.is_empty()
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            && !name.starts_with(|c: char| c.is_ascii_digit());
+        if !is_name || !is_method_call(call) {
+            return Some(Command::Unknown(line.to_owned()));
+        }
+        return match MethodCall::parse_with_variables(call, variables) {
+            Ok(msg) => Some(Command::Let(name.to_owned(), msg)),
+            Err(err) => Some(Command::SyntaxError(call.to_owned(), err)),
+        };
+    }
+
+    if is_method_call(line) {
+        return match MethodCall::parse_with_variables(line, variables) {
+            Ok(msg) => Some(Command::MethodCall(msg)),
+            Err(err) => Some(Command::SyntaxError(line.to_owned(), err)),
+        };
     }
 
     Some(Command::Unknown(line.to_owned()))
 }
 
+/// Parses the arguments of `wait`. The timeout is in seconds; 0 waits
+/// forever.
+fn parse_wait(args: &str, variables: &HashMap<String, Value>) -> Option<Command> {
+    let (event, rest) = split_word(args);
+    if !event.contains('.') {
+        return None;
+    }
+    let (timeout, rest) = match split_word(rest) {
+        (word, rest) if word.parse::<f64>().is_ok() => (word.parse::<f64>().ok(), rest),
+        _ => (None, rest),
+    };
+    let timeout = match timeout {
+        Some(secs) if secs.is_finite() && secs >= 0.0 => Some(Duration::from_secs_f64(secs)),
+        Some(_) => return None,
+        None => None,
+    };
+    let predicate = if rest.is_empty() {
+        None
+    } else {
+        match Predicate::parse_with_variables(rest, variables) {
+            Ok(predicate) => Some(predicate),
+            Err(err) => return Some(Command::SyntaxError(rest.to_owned(), err)),
+        }
+    };
+    Some(Command::Wait(Wait {
+        event: event.to_owned(),
+        timeout,
+        predicate,
+    }))
+}
+
+/// Splits off the first word of `s`.
+fn split_word(s: &str) -> (&str, &str) {
+    let s = s.trim_start();
+    let end = s.find(char::is_whitespace).unwrap_or(s.len());
+    (&s[..end], s[end..].trim_start())
+}
+
+/// Splits `let name = rest` into the name and the rest.
+pub(crate) fn split_let(line: &str) -> Option<(&str, &str)> {
+    const LET_COMMAND: &str = "let ";
+    let rest = line.trim_start().strip_prefix(LET_COMMAND)?;
+    let eq = rest.find('=')?;
+    Some((rest[..eq].trim(), rest[eq + 1..].trim_start()))
+}
+
+/// Whether `input` is a method call, possibly in `let`, that needs more lines
+/// to close its brackets.
+pub(crate) fn is_incomplete(input: &str) -> bool {
+    let call = split_let(input).map_or(input, |(_, call)| call);
+    is_method_call(call) && MethodCall::is_incomplete(call)
+}
+
+/// Whether `line` starts like `Domain.method`, so it should be parsed as a
+/// method call.
+fn is_method_call(line: &str) -> bool {
+    let line = line.trim_start();
+    let end = line
+        .find(|c: char| c.is_whitespace() || c == '(')
+        .unwrap_or(line.len());
+    line[..end].contains('.')
+}
+
 async fn execute_command(
     command: Command,
-    endpoints: &Endpoints,
-    target: &mut WebSocketTarget,
-) -> Result<(), Error> {
+    opt: &Opt,
+    endpoints: Option<&Endpoints>,
+    protocol: Option<&Protocol>,
+    conn: &mut Connection,
+    variables: &mut HashMap<String, Value>,
+) -> Result<Outcome, Error> {
+    // `wait` sees the events received since the previous command started, such
+    // as those caused by a method call that already returned.
+    if !matches!(command, Command::Wait(_)) {
+        conn.clear_events();
+    }
     match command {
-        Command::Version => {
-            let res = endpoints.version().await?;
-            println!("{:#?}", res);
-        }
-        Command::List => {
-            let res = endpoints.target_list().await?;
-            println!("{:#?}", res);
-        }
-        Command::NewTab(url) => {
-            let res = endpoints.open_new_tab(url).await?;
-            println!("{:#?}", res);
-        }
+        Command::Version => match endpoints {
+            Some(endpoints) => println!("{:#?}", endpoints.version().await?),
+            None => print_json(&call_browser(conn, "Browser.getVersion", Value::Null).await?)?,
+        },
+        Command::List => match endpoints {
+            Some(endpoints) => println!("{:#?}", endpoints.target_list().await?),
+            None => print_json(&call_browser(conn, "Target.getTargets", Value::Null).await?)?,
+        },
+        Command::NewTab(url) => match endpoints {
+            Some(endpoints) => println!("{:#?}", endpoints.open_new_tab(url).await?),
+            None => {
+                let params = serde_json::json!({ "url": url });
+                print_json(&call_browser(conn, "Target.createTarget", params).await?)?
+            }
+        },
         Command::ConnectTarget(url) => {
             let url = url::Url::parse(url.as_str())?;
-            *target = WebSocketTarget::connect(url).await?;
+            let mut new_conn = Connection::new(connect_target(url, opt).await?);
+            if conn.reports_disconnect {
+                new_conn.report_disconnect();
+            }
+            let old_conn = std::mem::replace(conn, new_conn);
+            old_conn.target.close().await?;
         }
-        Command::ActivateTarget(target_id) => {
-            endpoints.activate(target_id).await?;
+        Command::Attach(target_id) => {
+            let new_session = conn.target.attach(target_id).await?;
+            println!("Attached: {}", new_session.id());
+            conn.session = Some(new_session);
         }
-        Command::CloseTarget(target_id) => {
-            endpoints.close(target_id).await?;
+        Command::Detach => match conn.session.take() {
+            Some(old_session) => old_session.detach().await?,
+            None => println!("Not attached"),
+        },
+        Command::ActivateTarget(target_id) => match endpoints {
+            Some(endpoints) => endpoints.activate(target_id).await?,
+            None => {
+                let params = serde_json::json!({ "targetId": target_id });
+                call_browser(conn, "Target.activateTarget", params).await?;
+            }
+        },
+        Command::CloseTarget(target_id) => match endpoints {
+            Some(endpoints) => endpoints.close(target_id).await?,
+            None => {
+                let params = serde_json::json!({ "targetId": target_id });
+                call_browser(conn, "Target.closeTarget", params).await?;
+            }
+        },
+        Command::Help(None) => println!("{}", COMMANDS_HELP),
+        Command::Help(Some(topic)) => match protocol {
+            Some(protocol) => print_help(protocol, &topic),
+            None => println!("The protocol schema is not available"),
+        },
+        Command::MethodCall(method) => match call_method(&method, opt, protocol, conn).await? {
+            Some(result) => print_json(&result)?,
+            None => return Ok(Outcome::Invalid),
+        },
+        Command::Let(name, method) => match call_method(&method, opt, protocol, conn).await? {
+            Some(result) => {
+                variables.insert(name, result);
+            }
+            None => return Ok(Outcome::Invalid),
+        },
+        Command::Wait(wait) => {
+            if let (Some(protocol), false) = (protocol, opt.no_validate) {
+                if protocol.event(&wait.event).is_none() {
+                    eprintln!("Unknown event {}", wait.event);
+                    return Ok(Outcome::Invalid);
+                }
+            }
+            let timeout = match wait.timeout {
+                Some(timeout) => timeout,
+                None => Duration::from_secs(opt.timeout),
+            };
+            let event = conn.wait_event(&wait.event, wait.predicate.as_ref());
+            let event = if timeout == Duration::from_secs(0) {
+                event.await?
+            } else {
+                let timed_out = async {
+                    Timer::new(timeout).await;
+                    Err(Error::Timeout {
+                        method: wait.event.clone(),
+                        timeout,
+                    })
+                };
+                event.or(timed_out).await?
+            };
+            print_json(&event_json(event))?;
         }
-        Command::MethodCall(method) => {
-            println!("{:?}", method);
-            target.call_method(&method).await?;
+        Command::SyntaxError(line, err) => {
+            print_syntax_error(&line, &err);
+            return Ok(Outcome::Invalid);
         }
         Command::Unknown(line) => {
             println!("Unknown command: {}", line);
+            return Ok(Outcome::Invalid);
         }
     }
+    Ok(Outcome::Done)
+}
+
+/// Calls a method of the browser target. Stands in for the HTTP endpoints
+/// with `--pipe`. `Value::Null` params are sent as `{}`.
+async fn call_browser(conn: &Connection, method: &str, params: Value) -> Result<Value, Error> {
+    let params = match params {
+        Value::Null => serde_json::json!({}),
+        params => params,
+    };
+    conn.target
+        .call_method(&MethodCall::new(method, params))
+        .await
+}
+
+/// Validates the call unless `--no-validate` is given, then sends it to the
+/// current session or target. Returns `None` if the call is invalid.
+async fn call_method(
+    method: &MethodCall,
+    opt: &Opt,
+    protocol: Option<&Protocol>,
+    conn: &Connection,
+) -> Result<Option<Value>, Error> {
+    if let (Some(protocol), false) = (protocol, opt.no_validate) {
+        if let Err(errors) = protocol.validate(&method.method(), method.params()) {
+            print_validation_errors(&errors);
+            return Ok(None);
+        }
+    }
+    let result = match conn.session {
+        Some(ref session) => session.call_method(method).await?,
+        None => conn.target.call_method(method).await?,
+    };
+    Ok(Some(result))
+}
+
+/// Pretty-prints JSON, with colors if stdout is a terminal.
+fn print_json(value: &serde_json::Value) -> Result<(), Error> {
+    println!("{}", colored_json::to_colored_json_auto(value)?);
     Ok(())
 }
+
+/// Returns the exit code for the result of `run_command` or `run_repl`,
+/// reporting the error if there is one.
+pub(crate) fn exit_code(result: Result<i32, Error>) -> i32 {
+    match result {
+        Ok(code) => code,
+        Err(err) => {
+            print_error(&err);
+            EXIT_FAILURE
+        }
+    }
+}
+
+fn print_error(err: &Error) {
+    match err {
+        Error::Cdp(err) => {
+            eprintln!("Error {}: {}", err.code, err.message);
+            if let Some(ref data) = err.data {
+                eprintln!("{}", data);
+            }
+        }
+        Error::Timeout { .. } => {
+            eprintln!("Error: {}", err);
+            eprintln!("Use --timeout to wait longer.");
+        }
+        Error::Http { status, body } => {
+            eprintln!("Error: HTTP {}", status);
+            if !body.trim().is_empty() {
+                eprintln!("{}", body.trim());
+            }
+        }
+        _ => eprintln!("Error: {}", err),
+    }
+}
+
+fn print_syntax_error(input: &str, err: &ParseError) {
+    eprintln!("Syntax error: {}", err);
+    print_error_position(input, err);
+}
+
+/// Prints the line of `input` with the error and points at the offending
+/// character.
+fn print_error_position(input: &str, err: &ParseError) {
+    if let Some(line) = input.lines().nth(err.line - 1) {
+        eprintln!("  {}", line);
+        eprintln!("  {}^", " ".repeat(err.column - 1));
+    }
+}
+
+fn print_validation_errors(errors: &[ValidationError]) {
+    for err in errors {
+        eprintln!("Invalid call: {}", err);
+    }
+    eprintln!("Use --no-validate to send it anyway.");
+}
+
+const COMMANDS_HELP: &str = "\
+Commands:
+  version                 Show the browser version
+  list                    List targets
+  newtab <url>            Open a new tab
+  connect <url>           Connect to a target's WebSocket URL
+  attach <targetId>       Attach to a target over the browser connection
+  detach                  Detach from the current target
+  activate <targetId>     Bring a target to the front
+  close <targetId>        Close a target
+  help [Domain[.method]]  Describe a domain or a method
+  Domain.method({...})    Call a method
+  let <name> = Domain.method({...})
+                          Call a method and keep its result in a variable.
+                          Later parameters can refer to it as `$name.field`
+  wait Domain.event [timeout] [predicate]
+                          Wait for an event and show it. The timeout is in
+                          seconds and defaults to --timeout. The predicate
+                          tests its parameters, e.g. `frameId == $nav.frameId`";
+
+/// Describes a domain or a method.
+fn print_help(protocol: &Protocol, topic: &str) {
+    let (domain_name, method_name) = match topic.find('.') {
+        Some(dot) => (&topic[..dot], Some(&topic[dot + 1..])),
+        None => (topic, None),
+    };
+    let domain = protocol.domain(domain_name);
+    let command = method_name.and_then(|name| domain?.command(name));
+    match (domain, method_name, command) {
+        (Some(domain), None, _) => {
+            println!(
+                "{}{}",
+                domain.domain,
+                status_note(domain.experimental, domain.deprecated)
+            );
+            print_description(domain.description.as_deref(), "  ");
+            println!("Commands:");
+            for command in &domain.commands {
+                let note = status_note(command.experimental, command.deprecated);
+                println!("  {}{}", command.name, note);
+            }
+            if !domain.events.is_empty() {
+                println!("Events:");
+                for event in &domain.events {
+                    let note = status_note(event.experimental, event.deprecated);
+                    println!("  {}{}", event.name, note);
+                }
+            }
+        }
+        (Some(domain), Some(_), Some(command)) => {
+            let note = status_note(command.experimental, command.deprecated);
+            println!("{}.{}{}", domain.domain, command.name, note);
+            print_description(command.description.as_deref(), "  ");
+            let sections = [
+                ("Parameters", &command.parameters),
+                ("Returns", &command.returns),
+            ];
+            for (title, params) in sections.iter() {
+                if params.is_empty() {
+                    continue;
+                }
+                println!("{}:", title);
+                for param in params.iter() {
+                    println!(
+                        "  {}{}: {}{}",
+                        param.name,
+                        if param.optional { "?" } else { "" },
+                        describe_type(protocol, &domain.domain, &param.ty),
+                        status_note(param.experimental, param.deprecated)
+                    );
+                    print_description(param.description.as_deref(), "      ");
+                }
+            }
+        }
+        _ => println!("Unknown domain or method: {}", topic),
+    }
+}
+
+fn status_note(experimental: bool, deprecated: bool) -> &'static str {
+    match (experimental, deprecated) {
+        (false, false) => "",
+        (true, false) => " (experimental)",
+        (false, true) => " (deprecated)",
+        (true, true) => " (experimental, deprecated)",
+    }
+}
+
+fn print_description(description: Option<&str>, indent: &str) {
+    if let Some(description) = description {
+        for line in description.lines() {
+            println!("{}{}", indent, line.trim());
+        }
+    }
+}
+
+/// Describes a type for `help`, e.g. `string`, `array of Cookie` or
+/// `TransitionType ("link" | "typed" | ...)`.
+fn describe_type(protocol: &Protocol, domain: &str, ty: &Type) -> String {
+    if let Some(ref reference) = ty.reference {
+        // Show what simple named types stand for.
+        let target = protocol.type_def(domain, reference);
+        return match target {
+            Some(target) if target.ty.kind != Some(TypeKind::Object) => {
+                format!(
+                    "{} ({})",
+                    reference,
+                    describe_type(protocol, domain, &target.ty)
+                )
+            }
+            _ => reference.clone(),
+        };
+    }
+    if !ty.enum_values.is_empty() {
+        let values: Vec<String> = ty
+            .enum_values
+            .iter()
+            .map(|value| format!("{:?}", value))
+            .collect();
+        return values.join(" | ");
+    }
+    match (ty.kind, &ty.items) {
+        (Some(TypeKind::Array), Some(items)) => {
+            format!("array of {}", describe_type(protocol, domain, items))
+        }
+        (Some(kind), _) => format!("{:?}", kind).to_lowercase(),
+        (None, _) => "any".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cdp_rs::transport::{self, ChannelSender, Transport};
+    use cdp_rs::websocket::Message;
+    use futures::{future, SinkExt};
+    use serde_json::json;
+    use structopt::StructOpt;
+
+    async fn send(sender: &mut ChannelSender, value: Value) {
+        sender.send(Message::Text(value.to_string())).await.unwrap();
+    }
+
+    /// Options for a browser that isn't there.
+    fn unreachable_browser() -> Opt {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        drop(listener);
+        Opt::from_iter(&["cdp-cli", "--host", "127.0.0.1", "--port", &port])
+    }
+
+    #[test]
+    fn exit_codes() {
+        // Invalid calls are rejected before connecting.
+        let call = Subcommand::Call {
+            method: "Page.navigate({url: })".to_owned(),
+        };
+        let result = smol::run(run_command(call, unreachable_browser()));
+        assert_eq!(exit_code(result), EXIT_INVALID);
+
+        let call = Subcommand::Call {
+            method: "Page.navigate({url: 'about:blank'})".to_owned(),
+        };
+        let result = smol::run(run_command(call, unreachable_browser()));
+        assert_eq!(exit_code(result), EXIT_FAILURE);
+        let result = smol::run(run_command(Subcommand::List, unreachable_browser()));
+        assert_eq!(exit_code(result), EXIT_FAILURE);
+    }
+
+    #[test]
+    fn wait_for_events() {
+        smol::run(async {
+            let (client, browser) = transport::channel();
+            let (target, driver) = WebSocketTarget::new(client);
+            smol::Task::spawn(driver).detach();
+            let (mut sender, mut receiver) = browser.split();
+            let mut conn = Connection::new(target);
+
+            let attach = conn.target.attach("T");
+            let reply = async {
+                let call = match receiver.next().await {
+                    Some(Ok(Message::Text(text))) => serde_json::from_str::<Value>(&text).unwrap(),
+                    message => panic!("unexpected {:?}", message),
+                };
+                let reply = json!({"id": call["id"], "result": {"sessionId": "S"}});
+                send(&mut sender, reply).await;
+            };
+            let (session, ()) = future::join(attach, reply).await;
+            conn.session = Some(session.unwrap());
+
+            // Events of other sessions are skipped; those of the browser and
+            // of the current session are not.
+            let event = |n, session_id: Option<&str>| {
+                let mut event = json!({"method": "Page.e", "params": {"n": n}});
+                if let Some(session_id) = session_id {
+                    event["sessionId"] = session_id.into();
+                }
+                event
+            };
+            send(&mut sender, event(0, Some("other"))).await;
+            send(&mut sender, event(1, None)).await;
+            send(&mut sender, event(2, Some("S"))).await;
+            let received = conn.wait_event("Page.e", None).await.unwrap();
+            assert_eq!(received.params["n"], 1);
+            let received = conn.wait_event("Page.e", None).await.unwrap();
+            assert_eq!(received.params["n"], 2);
+
+            // Only the latest events are kept.
+            for n in 0..MAX_BUFFERED_EVENTS + 10 {
+                send(&mut sender, event(n, None)).await;
+            }
+            Timer::new(Duration::from_millis(100)).await;
+            assert_eq!(conn.events.len(), MAX_BUFFERED_EVENTS);
+            let received = conn.wait_event("Page.e", None).await.unwrap();
+            assert_eq!(received.params["n"], 10);
+
+            conn.clear_events();
+            assert!(conn.events.is_empty());
+        });
+    }
+}
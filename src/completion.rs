@@ -0,0 +1,379 @@
+use std::rc::Rc;
+
+use rustyline::completion::{Completer, Pair};
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::{Context, Helper};
+
+use cdp_rs::schema::Protocol;
+use cdp_rs::MethodCall;
+
+use crate::cli::{is_incomplete, split_let};
+
+/// REPL commands other than method calls. Those taking an argument end with
+/// a space.
+const COMMANDS: &[&str] = &[
+    "version",
+    "list",
+    "newtab ",
+    "connect ",
+    "attach ",
+    "detach",
+    "activate ",
+    "close ",
+    "help ",
+    "wait ",
+];
+
+/// Completes commands, domains, methods, parameter names and enum values
+/// using the protocol schema.
+pub(crate) struct ReplHelper {
+    protocol: Option<Rc<Protocol>>,
+}
+
+impl ReplHelper {
+    pub(crate) fn new(protocol: Option<Rc<Protocol>>) -> Self {
+        ReplHelper { protocol }
+    }
+
+    /// Completes the method or a parameter name of a method call.
+    fn complete_call(&self, line: &str) -> (usize, Vec<Pair>) {
+        match line.find('(') {
+            Some(lparen) => {
+                let (method, args) = (&line[..lparen], &line[lparen + 1..]);
+                let completion = self
+                    .complete_key(method, args)
+                    .or_else(|| self.complete_value(method, args));
+                let (start, candidates) = completion.unwrap_or((0, Vec::new()));
+                (lparen + 1 + start, candidates)
+            }
+            None if !line.contains(' ') => (0, self.complete_method(line, false)),
+            None => (line.len(), Vec::new()),
+        }
+    }
+
+    /// Completes the domain or the name of a method, or of an event if
+    /// `events` is true.
+    fn complete_method(&self, word: &str, events: bool) -> Vec<Pair> {
+        let protocol = match self.protocol {
+            Some(ref protocol) => protocol,
+            None => return Vec::new(),
+        };
+        match word.find('.') {
+            Some(dot) => {
+                let (domain, prefix) = (&word[..dot], &word[dot + 1..]);
+                let domain = match protocol.domain(domain) {
+                    Some(domain) => domain,
+                    None => return Vec::new(),
+                };
+                let names: Vec<&String> = if events {
+                    domain.events.iter().map(|event| &event.name).collect()
+                } else {
+                    domain
+                        .commands
+                        .iter()
+                        .map(|command| &command.name)
+                        .collect()
+                };
+                names
+                    .into_iter()
+                    .filter(|name| name.starts_with(prefix))
+                    .map(|name| {
+                        let method = format!("{}.{}", domain.domain, name);
+                        pair(method.clone(), method)
+                    })
+                    .collect()
+            }
+            None => protocol
+                .domains
+                .iter()
+                .filter(|domain| domain.domain.starts_with(word))
+                .map(|domain| pair(domain.domain.clone(), format!("{}.", domain.domain)))
+                .collect(),
+        }
+    }
+
+    /// Completes a key of the top-level object in the arguments of `method`.
+    /// `args` is the text after the opening parenthesis. Returns the start of
+    /// the key within `args`.
+    fn complete_key(&self, method: &str, args: &str) -> Option<(usize, Vec<Pair>)> {
+        let command = self.protocol.as_ref()?.command(method.trim())?;
+
+        let key_start = args
+            .trim_end_matches(|c: char| c.is_ascii_alphanumeric() || c == '_')
+            .len();
+        let prefix = &args[key_start..];
+        // Replace the opening quote too, if any.
+        let start = if args[..key_start].ends_with(&['"', '\''][..]) {
+            key_start - 1
+        } else {
+            key_start
+        };
+        let before = &args[..start];
+        if nesting_depth(before) != Some(1) || !before.trim_end().ends_with(&['{', ','][..]) {
+            return None;
+        }
+
+        let used = MethodCall::param_keys(before);
+        let candidates = command
+            .parameters
+            .iter()
+            .filter(|param| param.name.starts_with(prefix))
+            .filter(|param| !used.contains(&param.name))
+            .map(|param| pair(param.name.clone(), format!("\"{}\": ", param.name)))
+            .collect();
+        Some((start, candidates))
+    }
+
+    /// Completes the value of a top-level parameter of `method` that takes
+    /// one of a set of strings. Returns the start of the value, including its
+    /// opening quote, within `args`.
+    fn complete_value(&self, method: &str, args: &str) -> Option<(usize, Vec<Pair>)> {
+        let protocol = self.protocol.as_ref()?;
+        let method = method.trim();
+        let command = protocol.command(method)?;
+
+        let value_start = args.trim_end_matches(is_word_char).len();
+        let prefix = &args[value_start..];
+        let (start, quote) = match args[..value_start].chars().next_back() {
+            Some(quote) if quote == '"' || quote == '\'' => (value_start - 1, quote),
+            _ => (value_start, '"'),
+        };
+        let before = &args[..start];
+        let key = before.trim_end().strip_suffix(':')?.trim_end();
+        if nesting_depth(before) != Some(1) {
+            return None;
+        }
+        let key = key.trim_end_matches(&['"', '\''][..]);
+        let key = &key[key.trim_end_matches(is_word_char).len()..];
+
+        let param = command.parameters.iter().find(|param| param.name == key)?;
+        let values = match param.ty.reference {
+            Some(ref reference) => {
+                let domain = &method[..method.find('.')?];
+                &protocol.type_def(domain, reference)?.ty.enum_values
+            }
+            None => &param.ty.enum_values,
+        };
+        let candidates = values
+            .iter()
+            .filter(|value| value.starts_with(prefix))
+            .map(|value| pair(value.clone(), format!("{}{}{}", quote, value, quote)))
+            .collect();
+        Some((start, candidates))
+    }
+}
+
+fn pair(display: String, replacement: String) -> Pair {
+    Pair {
+        display,
+        replacement,
+    }
+}
+
+/// Whether `c` can be part of a key or an enum value.
+fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+/// Returns how deeply objects and arrays are nested at the end of `s`, or
+/// `None` if it ends inside a string. Strings may use either quote.
+fn nesting_depth(s: &str) -> Option<usize> {
+    let mut depth = 0usize;
+    let mut quote = None;
+    let mut escaped = false;
+    for c in s.chars() {
+        if let Some(q) = quote {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == q {
+                quote = None;
+            }
+            continue;
+        }
+        match c {
+            '"' | '\'' => quote = Some(c),
+            '{' | '[' => depth += 1,
+            '}' | ']' => depth = depth.saturating_sub(1),
+            _ => (),
+        }
+    }
+    match quote {
+        Some(_) => None,
+        None => Some(depth),
+    }
+}
+
+impl Completer for ReplHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let line = &line[..pos];
+
+        if let Some((_, call)) = split_let(line) {
+            let offset = line.len() - call.len();
+            let (start, candidates) = self.complete_call(call);
+            return Ok((offset + start, candidates));
+        }
+        if line.contains('(') {
+            return Ok(self.complete_call(line));
+        }
+
+        const HELP_COMMAND: &str = "help ";
+        const WAIT_COMMAND: &str = "wait ";
+        let (start, word) = if let Some(word) = line.strip_prefix(HELP_COMMAND) {
+            (HELP_COMMAND.len(), word)
+        } else if let Some(word) = line.strip_prefix(WAIT_COMMAND) {
+            (WAIT_COMMAND.len(), word)
+        } else {
+            (0, line)
+        };
+        if word.contains(' ') {
+            return Ok((pos, Vec::new()));
+        }
+
+        let mut candidates = Vec::new();
+        if start == 0 {
+            candidates.extend(
+                COMMANDS
+                    .iter()
+                    .filter(|command| command.starts_with(word))
+                    .map(|command| pair(command.trim_end().to_owned(), (*command).to_owned())),
+            );
+        }
+        let events = line.starts_with(WAIT_COMMAND);
+        candidates.extend(self.complete_method(word, events));
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for ReplHelper {}
+
+impl Highlighter for ReplHelper {}
+
+impl Validator for ReplHelper {
+    /// Keeps reading lines while a method call has unclosed brackets.
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        if is_incomplete(ctx.input()) {
+            Ok(ValidationResult::Incomplete)
+        } else {
+            Ok(ValidationResult::Valid(None))
+        }
+    }
+}
+
+impl Helper for ReplHelper {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn helper() -> ReplHelper {
+        let content = include_str!("../protocol/browser_protocol.json");
+        ReplHelper::new(Some(Rc::new(serde_json::from_str(content).unwrap())))
+    }
+
+    /// Returns the start and the replacements offered for a method call.
+    fn complete(helper: &ReplHelper, line: &str) -> (usize, Vec<String>) {
+        let (start, candidates) = helper.complete_call(line);
+        let replacements = candidates.into_iter().map(|pair| pair.replacement);
+        (start, replacements.collect())
+    }
+
+    #[test]
+    fn methods() {
+        let helper = helper();
+        let (start, domains) = complete(&helper, "Pag");
+        assert_eq!(start, 0);
+        assert!(domains.contains(&"Page.".to_owned()));
+        assert_eq!(
+            complete(&helper, "Page.navigateT"),
+            (0, vec!["Page.navigateToHistoryEntry".to_owned()])
+        );
+        let events = helper.complete_method("Page.loadE", true);
+        assert_eq!(events[0].replacement, "Page.loadEventFired");
+        assert!(complete(&helper, "Nope.").1.is_empty());
+    }
+
+    #[test]
+    fn keys() {
+        let helper = helper();
+        let line = "Page.navigate({ur";
+        assert_eq!(
+            complete(&helper, line),
+            (line.len() - 2, vec!["\"url\": ".to_owned()])
+        );
+        // The opening quote is replaced too.
+        let line = "Page.navigate({\"url\": 'a', 'referrerP";
+        assert_eq!(
+            complete(&helper, line),
+            (line.len() - 10, vec!["\"referrerPolicy\": ".to_owned()])
+        );
+
+        // Keys already given are not offered again, quoted or not.
+        for line in &[
+            "Page.navigate({url: 'a', ",
+            "Page.navigate({\"url\": 'a', ",
+            "Page.navigate({'url': 'a', ",
+        ] {
+            let (_, keys) = complete(&helper, line);
+            assert!(keys.contains(&"\"referrer\": ".to_owned()), "{}", line);
+            assert!(!keys.contains(&"\"url\": ".to_owned()), "{}", line);
+        }
+
+        // Only keys of the top-level object are completed.
+        assert!(complete(&helper, "Page.navigate({url: {").1.is_empty());
+        assert!(complete(&helper, "Page.navigate({url: 'a, ").1.is_empty());
+        assert!(complete(&helper, "Page.navigate({url: \"it's, ")
+            .1
+            .is_empty());
+    }
+
+    #[test]
+    fn enum_values() {
+        let helper = helper();
+        let line = "Page.navigate({transitionType: ";
+        let (start, values) = complete(&helper, line);
+        assert_eq!(start, line.len());
+        assert!(values.contains(&"\"typed\"".to_owned()));
+        let line = "Page.navigate({url: 'a', transitionType: 'ty";
+        assert_eq!(
+            complete(&helper, line),
+            (line.len() - 3, vec!["'typed'".to_owned()])
+        );
+        // Inline enums.
+        let line = "Emulation.setEmitTouchEventsForMouse({enabled: true, \"configuration\": \"mo";
+        assert_eq!(
+            complete(&helper, line),
+            (line.len() - 3, vec!["\"mobile\"".to_owned()])
+        );
+        assert!(complete(&helper, "Page.navigate({url: ").1.is_empty());
+    }
+
+    #[test]
+    fn incomplete_input() {
+        for input in &[
+            "Page.navigate(",
+            "Page.navigate({url: 'a',\n",
+            "let nav = Page.navigate({url: 'a',\n  referrer: 'b'",
+        ] {
+            assert!(is_incomplete(input), "{}", input);
+        }
+        for input in &[
+            "Page.navigate({url: 'a'})",
+            "Page.navigate({url: 'a",
+            "list",
+            "wait Page.loadEventFired",
+        ] {
+            assert!(!is_incomplete(input), "{}", input);
+        }
+    }
+}
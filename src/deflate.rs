@@ -0,0 +1,226 @@
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+
+use crate::Error;
+
+const EXTENSION_NAME: &str = "permessage-deflate";
+
+/// Every compressed message ends with an empty stored block, which is
+/// removed before sending.
+const TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// Options for negotiating the permessage-deflate extension (RFC 7692).
+#[derive(Debug, Clone)]
+pub struct DeflateConfig {
+    /// Resets our compression context after each message. Saves memory on
+    /// both sides at the cost of compression ratio.
+    pub client_no_context_takeover: bool,
+    /// Asks the browser to reset its compression context after each message.
+    pub server_no_context_takeover: bool,
+    /// Outgoing messages smaller than this are sent uncompressed.
+    pub min_compress_size: usize,
+}
+
+impl Default for DeflateConfig {
+    fn default() -> Self {
+        DeflateConfig {
+            client_no_context_takeover: false,
+            server_no_context_takeover: false,
+            min_compress_size: 64,
+        }
+    }
+}
+
+/// Parameters agreed on in the opening handshake.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct DeflateParams {
+    client_no_context_takeover: bool,
+    server_no_context_takeover: bool,
+}
+
+impl DeflateConfig {
+    /// Returns the value of `Sec-WebSocket-Extensions` to offer. We don't
+    /// offer `client_max_window_bits` so our window is always 15 bits.
+    pub(crate) fn offer(&self) -> String {
+        let mut offer = EXTENSION_NAME.to_owned();
+        if self.client_no_context_takeover {
+            offer.push_str("; client_no_context_takeover");
+        }
+        if self.server_no_context_takeover {
+            offer.push_str("; server_no_context_takeover");
+        }
+        offer
+    }
+
+    /// Validates the `Sec-WebSocket-Extensions` sent back by the browser.
+    pub(crate) fn accept(&self, response: &str) -> Result<DeflateParams, Error> {
+        let mut parts = response.split(';').map(str::trim);
+        if parts.next() != Some(EXTENSION_NAME) {
+            return Err(Error::Handshake(format!(
+                "Unexpected extension: {}",
+                response
+            )));
+        }
+
+        let mut params = DeflateParams {
+            client_no_context_takeover: false,
+            server_no_context_takeover: false,
+        };
+        let mut seen = Vec::new();
+        for part in parts {
+            let mut name_value = part.splitn(2, '=').map(str::trim);
+            let name = name_value.next().unwrap_or("");
+            let value = name_value.next();
+            if seen.contains(&name) {
+                return Err(Error::Handshake(format!(
+                    "Duplicate extension parameter: {}",
+                    name
+                )));
+            }
+            seen.push(name);
+
+            match (name, value) {
+                ("client_no_context_takeover", None) => params.client_no_context_takeover = true,
+                ("server_no_context_takeover", None) => params.server_no_context_takeover = true,
+                // The browser may use a smaller window than ours; inflating
+                // with 15 bits handles any of them.
+                ("server_max_window_bits", Some(bits)) => {
+                    let bits = bits.trim_matches('"');
+                    match bits.parse::<u8>() {
+                        Ok(8..=15) => (),
+                        _ => {
+                            return Err(Error::Handshake(format!(
+                                "Invalid server_max_window_bits: {}",
+                                bits
+                            )))
+                        }
+                    }
+                }
+                _ => {
+                    return Err(Error::Handshake(format!(
+                        "Unexpected extension parameter: {}",
+                        part
+                    )))
+                }
+            }
+        }
+
+        if self.server_no_context_takeover && !params.server_no_context_takeover {
+            return Err(Error::Handshake(
+                "server_no_context_takeover was not accepted".to_owned(),
+            ));
+        }
+        params.client_no_context_takeover |= self.client_no_context_takeover;
+        Ok(params)
+    }
+}
+
+/// Compresses outgoing messages.
+pub(crate) struct Compressor {
+    compress: Compress,
+    no_context_takeover: bool,
+    min_size: usize,
+}
+
+impl Compressor {
+    pub(crate) fn new(params: DeflateParams, config: &DeflateConfig) -> Self {
+        Compressor {
+            compress: Compress::new(Compression::default(), false),
+            no_context_takeover: params.client_no_context_takeover,
+            min_size: config.min_compress_size,
+        }
+    }
+
+    /// Whether `data` is worth compressing.
+    pub(crate) fn should_compress(&self, data: &[u8]) -> bool {
+        data.len() >= self.min_size
+    }
+
+    pub(crate) fn compress(&mut self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut out = Vec::with_capacity(data.len() / 2 + 64);
+        let start = self.compress.total_in();
+        loop {
+            if out.len() == out.capacity() {
+                out.reserve(out.capacity());
+            }
+            let consumed = (self.compress.total_in() - start) as usize;
+            self.compress
+                .compress_vec(&data[consumed..], &mut out, FlushCompress::Sync)
+                .map_err(|err| Error::Protocol(err.to_string()))?;
+            let consumed = (self.compress.total_in() - start) as usize;
+            // The flush is complete once there's room left in `out`.
+            if consumed == data.len() && out.len() < out.capacity() {
+                break;
+            }
+        }
+
+        if out.ends_with(&TAIL) {
+            out.truncate(out.len() - TAIL.len());
+        }
+        if self.no_context_takeover {
+            self.compress.reset();
+        }
+        Ok(out)
+    }
+}
+
+/// Decompresses incoming messages.
+pub(crate) struct Decompressor {
+    decompress: Decompress,
+    no_context_takeover: bool,
+}
+
+impl Decompressor {
+    pub(crate) fn new(params: DeflateParams) -> Self {
+        Decompressor {
+            decompress: Decompress::new(false),
+            no_context_takeover: params.server_no_context_takeover,
+        }
+    }
+
+    /// Decompresses a message. Stops early once the output exceeds `limit`
+    /// bytes so that the caller can reject it without inflating all of it.
+    pub(crate) fn decompress(
+        &mut self,
+        data: &[u8],
+        limit: Option<usize>,
+    ) -> Result<Vec<u8>, Error> {
+        let mut input = Vec::with_capacity(data.len() + TAIL.len());
+        input.extend_from_slice(data);
+        input.extend_from_slice(&TAIL);
+
+        let mut out = Vec::with_capacity(data.len() * 2 + 64);
+        let start = self.decompress.total_in();
+        loop {
+            if out.len() == out.capacity() {
+                out.reserve(out.capacity());
+            }
+            let consumed = (self.decompress.total_in() - start) as usize;
+            let written = out.len();
+            let status = self
+                .decompress
+                .decompress_vec(&input[consumed..], &mut out, FlushDecompress::Sync)
+                .map_err(|err| Error::Protocol(err.to_string()))?;
+            if limit.is_some_and(|limit| out.len() > limit) {
+                break;
+            }
+            if let Status::StreamEnd = status {
+                // The browser ended the stream; start a new one for the next
+                // message.
+                self.decompress.reset(false);
+                break;
+            }
+            let consumed_now = (self.decompress.total_in() - start) as usize;
+            if consumed_now == input.len() && out.len() < out.capacity() {
+                break;
+            }
+            if consumed_now == consumed && out.len() == written {
+                return Err(Error::Protocol("Invalid compressed data".to_owned()));
+            }
+        }
+
+        if self.no_context_takeover {
+            self.decompress.reset(false);
+        }
+        Ok(out)
+    }
+}
@@ -0,0 +1,179 @@
+//! Finding browsers that are already running.
+
+use std::env;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use async_io::Timer;
+use futures::future::{self, Either};
+use futures_lite::*;
+use serde::Serialize;
+
+use crate::{BrowserVersionMetadata, Endpoints, Error};
+
+/// The file a browser writes to its user data directory when started with
+/// `--remote-debugging-port`.
+pub const ACTIVE_PORT_FILE: &str = "DevToolsActivePort";
+
+/// The host a browser listens on unless told otherwise.
+const LOCAL_HOST: &str = "127.0.0.1";
+
+/// How long a port may take to answer `/json/version` during a scan.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How many ports are probed at once.
+const PROBE_BATCH: usize = 64;
+
+/// The contents of `DevToolsActivePort`: the port DevTools listens on and the
+/// path of the browser target's WebSocket.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActivePort {
+    pub port: u16,
+    /// E.g. `/devtools/browser/9b1c4f1e-...`.
+    pub browser_path: String,
+}
+
+impl ActivePort {
+    pub fn parse(content: &str) -> Result<Self, Error> {
+        let invalid = || Error::InvalidResponse(format!("Invalid {}", ACTIVE_PORT_FILE));
+        let mut lines = content.lines();
+        let port = lines.next().ok_or_else(invalid)?;
+        let port = port.trim().parse().map_err(|_| invalid())?;
+        let browser_path = lines.next().unwrap_or("").trim().to_owned();
+        Ok(ActivePort { port, browser_path })
+    }
+
+    /// Reads `DevToolsActivePort` in `user_data_dir`. The file is left
+    /// behind if the browser crashes, so the port may be stale.
+    pub fn read(user_data_dir: impl AsRef<Path>) -> impl Future<Output = Result<Self, Error>> {
+        let path = user_data_dir.as_ref().join(ACTIVE_PORT_FILE);
+        async move {
+            let content = blocking::unblock(move || std::fs::read_to_string(path)).await?;
+            ActivePort::parse(&content)
+        }
+    }
+
+    /// The WebSocket URL of the browser target on `host`.
+    pub fn websocket_url(&self, host: &str) -> String {
+        format!("ws://{}:{}{}", host, self.port, self.browser_path)
+    }
+}
+
+impl Endpoints {
+    /// The endpoints of a local browser using `user_data_dir` as its profile.
+    pub fn from_user_data_dir(
+        user_data_dir: impl AsRef<Path>,
+    ) -> impl Future<Output = Result<Endpoints, Error>> {
+        let active_port = ActivePort::read(user_data_dir);
+        async move {
+            let active_port = active_port.await?;
+            Ok(Endpoints::new(LOCAL_HOST, active_port.port, None))
+        }
+    }
+}
+
+/// The default user data directories of Chrome, Chromium and Edge on Linux.
+pub fn default_user_data_dirs() -> Vec<PathBuf> {
+    let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => match env::var_os("HOME") {
+            Some(home) => Path::new(&home).join(".config"),
+            None => return Vec::new(),
+        },
+    };
+    [
+        "google-chrome",
+        "google-chrome-beta",
+        "google-chrome-unstable",
+        "chromium",
+        "microsoft-edge",
+    ]
+    .iter()
+    .map(|name| config_dir.join(name))
+    .collect()
+}
+
+/// A browser that answered on a port.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiscoveredBrowser {
+    pub host: String,
+    pub port: u16,
+    /// The profile whose `DevToolsActivePort` named the port, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_data_dir: Option<PathBuf>,
+    pub version: BrowserVersionMetadata,
+}
+
+/// Finds browsers listening on the ports in the default user data
+/// directories, then on `ports` of `host`. Each port is listed once.
+pub fn discover(
+    host: impl Into<String>,
+    ports: RangeInclusive<u16>,
+) -> impl Future<Output = Vec<DiscoveredBrowser>> {
+    let host = host.into();
+    async move {
+        let mut found = Vec::new();
+        for dir in default_user_data_dirs() {
+            let active_port = match ActivePort::read(&dir).await {
+                Ok(active_port) => active_port,
+                Err(_) => continue,
+            };
+            if let Some(mut browser) = probe(LOCAL_HOST.to_owned(), active_port.port).await {
+                browser.user_data_dir = Some(dir);
+                found.push(browser);
+            }
+        }
+
+        let ports: Vec<u16> = ports.collect();
+        for batch in ports.chunks(PROBE_BATCH) {
+            let probes = batch.iter().map(|port| probe(host.clone(), *port));
+            for browser in future::join_all(probes).await.into_iter().flatten() {
+                if !found.iter().any(|known| known.port == browser.port) {
+                    found.push(browser);
+                }
+            }
+        }
+        found
+    }
+}
+
+/// Asks `host:port` for `/json/version`, giving up after `PROBE_TIMEOUT`.
+async fn probe(host: String, port: u16) -> Option<DiscoveredBrowser> {
+    let version = Endpoints::new(host.as_str(), port, None).version();
+    match future::select(Box::pin(version), Timer::new(PROBE_TIMEOUT)).await {
+        Either::Left((Ok(version), _)) => Some(DiscoveredBrowser {
+            host,
+            port,
+            user_data_dir: None,
+            version,
+        }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn active_port() {
+        let content = "35719\n/devtools/browser/9b1c4f1e-6ec4-4d6d-a1e4-7e0b5c1a7a3c\n";
+        let active_port = ActivePort::parse(content).unwrap();
+        assert_eq!(active_port.port, 35719);
+        assert_eq!(
+            active_port.websocket_url("127.0.0.1"),
+            "ws://127.0.0.1:35719/devtools/browser/9b1c4f1e-6ec4-4d6d-a1e4-7e0b5c1a7a3c"
+        );
+        assert!(ActivePort::parse("").is_err());
+        assert!(ActivePort::parse("x\n/devtools/browser/a").is_err());
+
+        let dir = std::env::temp_dir().join(format!("cdp-rs-active-port-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(ACTIVE_PORT_FILE), content).unwrap();
+        let read = smol::run(ActivePort::read(&dir)).unwrap();
+        assert_eq!(read, active_port);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,10 +1,11 @@
-use async_net::TcpStream;
-use serde::Deserialize;
-use smol::{io, prelude::*};
+use futures_lite::*;
+use serde::{Deserialize, Serialize};
 
+use crate::schema::{Protocol, SchemaCache};
+use crate::tls::{self, MaybeTlsStream, TlsConfig};
 use crate::Error;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct TargetItem {
     pub description: String,
@@ -18,7 +19,7 @@ pub struct TargetItem {
     pub websocket_debugger_url: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct BrowserVersionMetadata {
     #[serde(rename = "Browser")]
     pub browser: String,
@@ -37,8 +38,8 @@ pub struct BrowserVersionMetadata {
 const MAX_HEADERS: usize = 64;
 const MAX_HEADER_LEN: usize = 8192;
 
-pub(crate) async fn read_raw_header(
-    reader: &mut io::BufReader<&TcpStream>,
+pub(crate) async fn read_raw_header<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
     buf: &mut Vec<u8>,
 ) -> Result<(), Error> {
     loop {
@@ -53,14 +54,17 @@ pub(crate) async fn read_raw_header(
         }
 
         if len > MAX_HEADER_LEN {
-            return Err(format!("Header too large").into());
+            return Err(Error::InvalidResponse("Header too large".to_owned()));
         }
     }
     Ok(())
 }
 
-async fn endpoint_response(stream: &TcpStream) -> Result<Vec<u8>, Error> {
+/// Reads a response and returns its body. Unless `json` is false, the body
+/// must be JSON; `/json/activate` and `/json/close` answer with plain text.
+async fn endpoint_response(stream: &mut MaybeTlsStream, json: bool) -> Result<Vec<u8>, Error> {
     let mut reader = io::BufReader::new(stream);
+    let invalid_response = |reason: &str| Error::InvalidResponse(reason.to_owned());
 
     // Read http header
     let mut buf = Vec::new();
@@ -69,86 +73,116 @@ async fn endpoint_response(stream: &TcpStream) -> Result<Vec<u8>, Error> {
     // Parse
     let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
     let mut response = httparse::Response::new(&mut headers);
-    match response.parse(&buf)? {
-        httparse::Status::Partial => {
-            return Err(format!("Invalid header").into());
-        }
-        httparse::Status::Complete(_) => (),
-    }
-
-    if response.code.unwrap_or(0) != 200 {
-        return Err(format!("Response != 200").into());
+    match response.parse(&buf) {
+        Ok(httparse::Status::Complete(_)) => (),
+        Ok(httparse::Status::Partial) | Err(_) => return Err(invalid_response("Invalid header")),
     }
+    let status = response.code.unwrap_or(0);
 
     // Headers
-    let mut content_length = 0;
+    let mut content_length = None;
     let mut content_type = None;
+    // Proxies may change the case of header names.
     for header in response.headers {
-        match header.name {
-            "Content-Length" => {
-                let value = std::str::from_utf8(header.value)?;
-                content_length = value.parse::<usize>()?;
-            }
-            "Content-Type" => {
-                let value = std::str::from_utf8(header.value)?;
-                content_type = Some(value);
-            }
-            _ => (),
+        if header.name.eq_ignore_ascii_case("Content-Length") {
+            let value = std::str::from_utf8(header.value).ok();
+            let value = value.and_then(|value| value.trim().parse::<usize>().ok());
+            content_length = Some(value.ok_or_else(|| invalid_response("Invalid Content-Length"))?);
+        } else if header.name.eq_ignore_ascii_case("Content-Type") {
+            let value = std::str::from_utf8(header.value).unwrap_or("");
+            content_type = Some(value.to_owned());
         }
     }
 
-    if content_length == 0 {
-        return Err(format!("No content").into());
-    }
-    if content_type.unwrap_or("") != "application/json; charset=UTF-8" {
-        return Err(format!("Content is not json").into());
+    // The connection is closed after the response, so the body may be
+    // delimited by EOF.
+    let mut body = Vec::new();
+    match content_length {
+        Some(len) => {
+            body.resize(len, 0);
+            reader.read_exact(&mut body).await?;
+        }
+        None => {
+            reader.read_to_end(&mut body).await?;
+        }
     }
 
-    let mut buf = vec![0; content_length];
-    reader.read_exact(&mut buf).await?;
+    if status != 200 {
+        let body = String::from_utf8_lossy(&body).into_owned();
+        return Err(Error::Http { status, body });
+    }
+    if !json {
+        return Ok(body);
+    }
+    if body.is_empty() {
+        return Err(invalid_response("No content"));
+    }
+    let is_json = content_type.is_some_and(|value| {
+        let mime = value.split(';').next().unwrap_or("");
+        mime.trim().eq_ignore_ascii_case("application/json")
+    });
+    if !is_json {
+        return Err(invalid_response("Content is not json"));
+    }
 
-    Ok(buf)
+    Ok(body)
 }
 
-async fn send_request(stream: &mut TcpStream, host: &str, path: &str) -> Result<Vec<u8>, Error> {
+async fn send_request(
+    stream: &mut MaybeTlsStream,
+    host: &str,
+    port: u16,
+    path: &str,
+    json: bool,
+) -> Result<Vec<u8>, Error> {
     let path = format!(
-        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
-        path, host
+        "GET {} HTTP/1.1\r\nHost: {}:{}\r\nConnection: close\r\n\r\n",
+        path, host, port
     );
     stream.write_all(path.as_bytes()).await?;
-    let content = endpoint_response(&stream).await?;
+    let content = endpoint_response(stream, json).await?;
     Ok(content)
 }
 
+/// HTTP endpoints of a browser. Each request uses a new connection since
+/// the browser closes it after responding.
 #[derive(Clone)]
 pub struct Endpoints {
     host: String,
     port: u16,
-    stream: TcpStream,
+    tls: Option<TlsConfig>,
 }
 
 impl Endpoints {
-    pub(crate) async fn new(host: impl Into<String>, port: u16) -> Result<Self, Error> {
+    /// Uses HTTPS if `tls` is given.
+    pub fn new(host: impl Into<String>, port: u16, tls: Option<TlsConfig>) -> Self {
         let host = host.into();
-        let stream = TcpStream::connect(&format!("{}:{}", host, port)).await?;
-        Ok(Endpoints { host, port, stream })
+        Endpoints { host, port, tls }
     }
 
-    pub fn version(&self) -> impl Future<Output = Result<BrowserVersionMetadata, Error>> {
-        let mut stream = self.stream.clone();
+    fn request(&self, path: String, json: bool) -> impl Future<Output = Result<Vec<u8>, Error>> {
         let host = self.host.clone();
+        let port = self.port;
+        let tls = self.tls.clone();
+        async move {
+            let mut stream = tls::connect(&host, port, tls.as_ref()).await?;
+            send_request(&mut stream, &host, port, &path, json).await
+        }
+    }
+
+    pub fn version(&self) -> impl Future<Output = Result<BrowserVersionMetadata, Error>> {
+        let request = self.request("/json/version".to_owned(), true);
         async move {
-            let content = send_request(&mut stream, &host, "/json/version").await?;
+            let content = request.await?;
             let version: BrowserVersionMetadata = serde_json::from_slice(&content)?;
             Ok(version)
         }
     }
 
     pub fn target_list(&self) -> impl Future<Output = Result<Vec<TargetItem>, Error>> {
-        let mut stream = self.stream.clone();
-        let host = self.host.clone();
+        let request = self.request("/json/list".to_owned(), true);
         async move {
-            let content = send_request(&mut stream, &host, "/json/list").await?;
+            let content = request.await?;
             let targets: Vec<TargetItem> = serde_json::from_slice(&content)?;
             Ok(targets)
         }
@@ -158,33 +192,128 @@ impl Endpoints {
         &self,
         url: impl AsRef<str>,
     ) -> impl Future<Output = Result<TargetItem, Error>> {
-        let mut stream = self.stream.clone();
-        let path = format!("/json/new?{}", url.as_ref());
-        let host = self.host.clone();
+        let request = self.request(format!("/json/new?{}", url.as_ref()), true);
         async move {
-            let content = send_request(&mut stream, &host, &path).await?;
+            let content = request.await?;
             let target: TargetItem = serde_json::from_slice(&content)?;
             Ok(target)
         }
     }
 
     pub fn activate(&self, target_id: impl AsRef<str>) -> impl Future<Output = Result<(), Error>> {
-        let mut stream = self.stream.clone();
-        let path = format!("/json/activate/{}", target_id.as_ref());
-        let host = self.host.clone();
+        let request = self.request(format!("/json/activate/{}", target_id.as_ref()), false);
         async move {
-            let _content = send_request(&mut stream, &host, &path).await?;
+            let _content = request.await?;
             Ok(())
         }
     }
 
     pub fn close(&self, target_id: impl AsRef<str>) -> impl Future<Output = Result<(), Error>> {
-        let mut stream = self.stream.clone();
-        let path = format!("/json/activate/{}", target_id.as_ref());
-        let host = self.host.clone();
+        let request = self.request(format!("/json/close/{}", target_id.as_ref()), false);
         async move {
-            let _content = send_request(&mut stream, &host, &path).await?;
+            let _content = request.await?;
             Ok(())
         }
     }
+
+    /// The protocol schema the browser implements.
+    pub fn protocol(&self) -> impl Future<Output = Result<Protocol, Error>> {
+        let request = self.request("/json/protocol".to_owned(), true);
+        async move {
+            let content = request.await?;
+            let protocol: Protocol = serde_json::from_slice(&content)?;
+            Ok(protocol)
+        }
+    }
+
+    /// Like `protocol`, but reuses a schema cached for the same browser
+    /// version. The cache is best effort: if it can't be read or written, the
+    /// schema is fetched from the browser.
+    pub fn protocol_cached(
+        &self,
+        cache: &SchemaCache,
+    ) -> impl Future<Output = Result<Protocol, Error>> {
+        let endpoints = self.clone();
+        let cache = cache.clone();
+        async move {
+            let version = endpoints.version().await?;
+            let (protocol_version, v8_version) = (&version.protocol_version, &version.v8_version);
+            if let Ok(Some(protocol)) = cache.load(protocol_version, v8_version).await {
+                return Ok(protocol);
+            }
+            let protocol = endpoints.protocol().await?;
+            let _ = cache.store(protocol_version, v8_version, &protocol).await;
+            Ok(protocol)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_net::TcpListener;
+
+    /// Sends a request to a server that answers it with `body` of
+    /// `content_type`. Returns the result and the request line.
+    fn with_response<T>(
+        content_type: &str,
+        body: &str,
+        send: impl FnOnce(Endpoints) -> future::Boxed<Result<T, Error>>,
+    ) -> (Result<T, Error>, String) {
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+            content_type,
+            body.len(),
+            body
+        );
+        smol::run(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let server = smol::Task::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut reader = io::BufReader::new(stream);
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).await.unwrap();
+                let mut line = String::new();
+                while line != "\r\n" {
+                    line.clear();
+                    reader.read_line(&mut line).await.unwrap();
+                }
+                let mut stream = reader.into_inner();
+                stream.write_all(response.as_bytes()).await.unwrap();
+                request_line.trim_end().to_owned()
+            });
+            let result = send(Endpoints::new("127.0.0.1", port, None)).await;
+            (result, server.await)
+        })
+    }
+
+    #[test]
+    fn plain_text_replies() {
+        // Chrome answers these two with text rather than JSON.
+        let (result, request) = with_response("text/plain", "Target activated", |endpoints| {
+            endpoints.activate("T1").boxed()
+        });
+        result.unwrap();
+        assert_eq!(request, "GET /json/activate/T1 HTTP/1.1");
+        let (result, request) = with_response("text/plain", "Target is closing", |endpoints| {
+            endpoints.close("T1").boxed()
+        });
+        result.unwrap();
+        assert_eq!(request, "GET /json/close/T1 HTTP/1.1");
+
+        // Others must be JSON.
+        let body = r#"{"Browser": "Chrome"}"#;
+        let (result, _) =
+            with_response("text/plain", body, |endpoints| endpoints.version().boxed());
+        match result {
+            Err(Error::InvalidResponse(reason)) => assert_eq!(reason, "Content is not json"),
+            res => panic!("unexpected {:?}", res.map(|_| ())),
+        }
+        let (result, request) = with_response("application/json", "[]", |endpoints| {
+            endpoints.target_list().boxed()
+        });
+        assert!(result.unwrap().is_empty());
+        assert_eq!(request, "GET /json/list HTTP/1.1");
+    }
 }
@@ -0,0 +1,133 @@
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+use crate::websocket_target::MethodError;
+
+/// Errors returned by endpoints, connections and sessions.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Reading from or writing to the transport failed.
+    Io(io::Error),
+    /// TLS could not be set up, e.g. the CA file is invalid.
+    Tls(String),
+    /// A URL or host name could not be used.
+    InvalidUrl(String),
+    /// An HTTP endpoint replied with a status other than 200.
+    Http { status: u16, body: String },
+    /// A reply from the browser could not be understood.
+    InvalidResponse(String),
+    /// The WebSocket opening handshake failed.
+    Handshake(String),
+    /// The browser violated the WebSocket protocol.
+    Protocol(String),
+    /// A message was not valid JSON.
+    Json(serde_json::Error),
+    /// DevTools replied to a method call with an error object.
+    Cdp(MethodError),
+    /// A method call got no reply in time.
+    Timeout { method: String, timeout: Duration },
+    /// The connection or session is closed.
+    Disconnected(String),
+    /// A browser could not be started.
+    Launch(String),
+    /// A message sent to a `Replayer` differs from the recording.
+    Replay(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "I/O error: {}", err),
+            Error::Tls(reason) => write!(f, "TLS error: {}", reason),
+            Error::InvalidUrl(reason) => write!(f, "Invalid URL: {}", reason),
+            Error::Http { status, body } if body.trim().is_empty() => write!(f, "HTTP {}", status),
+            Error::Http { status, body } => write!(f, "HTTP {}: {}", status, body.trim()),
+            Error::InvalidResponse(reason) => write!(f, "Invalid response: {}", reason),
+            Error::Handshake(reason) => write!(f, "WebSocket handshake failed: {}", reason),
+            Error::Protocol(reason) => write!(f, "WebSocket protocol error: {}", reason),
+            Error::Json(err) => write!(f, "Invalid JSON: {}", err),
+            Error::Cdp(err) => write!(f, "{}", err),
+            Error::Timeout { method, timeout } => {
+                write!(f, "{} timed out after {:?}", method, timeout)
+            }
+            Error::Disconnected(reason) => write!(f, "Disconnected: {}", reason),
+            Error::Launch(reason) => write!(f, "Failed to launch the browser: {}", reason),
+            Error::Replay(reason) => write!(f, "Replay failed: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            Error::Json(err) => Some(err),
+            Error::Cdp(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::Json(err)
+    }
+}
+
+impl From<url::ParseError> for Error {
+    fn from(err: url::ParseError) -> Self {
+        Error::InvalidUrl(err.to_string())
+    }
+}
+
+impl From<MethodError> for Error {
+    fn from(err: MethodError) -> Self {
+        Error::Cdp(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as _;
+
+    #[test]
+    fn conversions_and_messages() {
+        let err = Error::from(io::Error::from(io::ErrorKind::UnexpectedEof));
+        assert!(matches!(err, Error::Io(_)));
+        assert!(err.source().is_some());
+
+        let err = Error::from(serde_json::from_str::<u32>("x").unwrap_err());
+        assert!(err.to_string().starts_with("Invalid JSON: "));
+        assert!(err.source().is_some());
+
+        let err = Error::from(url::Url::parse("not a url").unwrap_err());
+        assert!(matches!(err, Error::InvalidUrl(_)));
+
+        let err = Error::Http {
+            status: 404,
+            body: " \n".to_owned(),
+        };
+        assert_eq!(err.to_string(), "HTTP 404");
+        let err = Error::Http {
+            status: 500,
+            body: "No such target\n".to_owned(),
+        };
+        assert_eq!(err.to_string(), "HTTP 500: No such target");
+
+        let err = Error::Timeout {
+            method: "Page.navigate".to_owned(),
+            timeout: Duration::from_secs(3),
+        };
+        assert_eq!(err.to_string(), "Page.navigate timed out after 3s");
+        assert!(err.source().is_none());
+    }
+}
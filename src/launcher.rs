@@ -0,0 +1,533 @@
+//! Starts a Chrome or Chromium process to connect to.
+
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use async_io::Timer;
+use futures::channel::oneshot;
+use futures::future::{self, Either};
+use futures_lite::*;
+use url::Url;
+
+use crate::pipe;
+#[cfg(any(feature = "async-std", feature = "smol", feature = "tokio"))]
+use crate::runtime;
+use crate::websocket;
+use crate::{Driver, Endpoints, Error, WebSocketTarget};
+
+/// Environment variable naming the browser executable to launch.
+pub const EXECUTABLE_ENV: &str = "CHROME_PATH";
+
+/// Names of browser executables looked up in `PATH`.
+const EXECUTABLE_NAMES: &[&str] = &[
+    "google-chrome",
+    "google-chrome-stable",
+    "chromium",
+    "chromium-browser",
+    "chrome",
+    "microsoft-edge",
+];
+
+/// Where browsers are usually installed, for when they aren't in `PATH`.
+const INSTALL_PATHS: &[&str] = &[
+    "/Applications/Google Chrome.app/Contents/MacOS/Google Chrome",
+    "/Applications/Chromium.app/Contents/MacOS/Chromium",
+    "/Applications/Microsoft Edge.app/Contents/MacOS/Microsoft Edge",
+    "/snap/bin/chromium",
+    r"C:\Program Files\Google\Chrome\Application\chrome.exe",
+    r"C:\Program Files (x86)\Google\Chrome\Application\chrome.exe",
+    r"C:\Program Files (x86)\Microsoft\Edge\Application\msedge.exe",
+];
+
+/// The line a browser prints to stderr once DevTools is listening.
+const LISTENING_PREFIX: &str = "DevTools listening on ";
+
+/// How to start a browser.
+#[derive(Debug, Clone)]
+pub struct LaunchOptions {
+    /// The browser executable. Found with `find_executable` if not given.
+    pub executable: Option<PathBuf>,
+    /// Run without a window.
+    pub headless: bool,
+    /// The profile directory. A temporary one is created, and removed when
+    /// the browser is dropped, if not given.
+    pub user_data_dir: Option<PathBuf>,
+    /// Extra command line arguments.
+    pub args: Vec<String>,
+    /// Talk to DevTools over pipes (`--remote-debugging-pipe`) instead of a
+    /// TCP port, so that no other process can connect. Unix only.
+    pub pipe: bool,
+    /// How long to wait for DevTools to start listening.
+    pub startup_timeout: Duration,
+}
+
+impl Default for LaunchOptions {
+    fn default() -> Self {
+        LaunchOptions {
+            executable: None,
+            headless: true,
+            user_data_dir: None,
+            args: Vec::new(),
+            pipe: false,
+            startup_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Finds a Chromium-family browser: the one named by `CHROME_PATH`, then the
+/// first known executable in `PATH`, then the usual install locations.
+pub fn find_executable() -> Option<PathBuf> {
+    if let Some(path) = env::var_os(EXECUTABLE_ENV) {
+        return Some(PathBuf::from(path));
+    }
+    if let Some(paths) = env::var_os("PATH") {
+        for dir in env::split_paths(&paths) {
+            for name in EXECUTABLE_NAMES {
+                let path = dir.join(name).with_extension(env::consts::EXE_EXTENSION);
+                if path.is_file() {
+                    return Some(path);
+                }
+            }
+        }
+    }
+    INSTALL_PATHS
+        .iter()
+        .map(PathBuf::from)
+        .find(|path| path.is_file())
+}
+
+/// A browser process started by `Browser::launch`. Dropping it kills the
+/// process and removes its temporary profile.
+pub struct Browser {
+    child: Child,
+    /// `None` with pipes.
+    websocket_url: Option<Url>,
+    pipe: Option<(pipe::Sender, pipe::Receiver)>,
+    temp_dir: Option<PathBuf>,
+}
+
+impl std::fmt::Debug for Browser {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Browser")
+            .field("child", &self.child)
+            .field("websocket_url", &self.websocket_url)
+            .field("temp_dir", &self.temp_dir)
+            .finish()
+    }
+}
+
+impl Browser {
+    /// Starts a browser with DevTools listening on a free port, and waits
+    /// until it reports the URL of its WebSocket. With pipes, returns as soon
+    /// as the browser is started.
+    pub fn launch(options: &LaunchOptions) -> impl Future<Output = Result<Browser, Error>> {
+        let options = options.clone();
+        async move {
+            let executable = match options.executable.clone().or_else(find_executable) {
+                Some(executable) => executable,
+                None => {
+                    let message = format!(
+                        "No Chrome or Chromium executable found. Set {} to its path.",
+                        EXECUTABLE_ENV
+                    );
+                    return Err(Error::Launch(message));
+                }
+            };
+            let temp_dir = match options.user_data_dir {
+                Some(_) => None,
+                None => Some(create_temp_dir()?),
+            };
+            let user_data_dir = options.user_data_dir.as_ref().or(temp_dir.as_ref());
+
+            let mut command = Command::new(&executable);
+            let pipes = if options.pipe {
+                command.arg("--remote-debugging-pipe");
+                Some(pass_pipes(&mut command)?)
+            } else {
+                command.arg("--remote-debugging-port=0");
+                None
+            };
+            command
+                .arg("--no-first-run")
+                .arg("--no-default-browser-check");
+            if let Some(dir) = user_data_dir {
+                command.arg(format!("--user-data-dir={}", dir.display()));
+            }
+            if options.headless {
+                command.arg("--headless");
+            }
+            command
+                .args(&options.args)
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::piped());
+            let child = match command.spawn() {
+                Ok(child) => child,
+                Err(err) => {
+                    if let Some(ref dir) = temp_dir {
+                        let _ = fs::remove_dir_all(dir);
+                    }
+                    let message = format!("Failed to run {}: {}", executable.display(), err);
+                    return Err(Error::Launch(message));
+                }
+            };
+            // From here on, dropping the browser cleans up.
+            let mut browser = Browser {
+                child,
+                websocket_url: None,
+                pipe: None,
+                temp_dir,
+            };
+            let stderr = browser.child.stderr.take().unwrap();
+            let listening = watch_stderr(stderr);
+            if let Some((reader, writer)) = pipes {
+                // Drop the ends the browser uses, so that reads end when it
+                // exits.
+                drop(command);
+                browser.pipe = Some(pipe::new(reader, writer));
+                return Ok(browser);
+            }
+            let timeout = Timer::new(options.startup_timeout);
+            let websocket_url = match future::select(listening, timeout).await {
+                Either::Left((Ok(Ok(url)), _)) => url,
+                Either::Left((Ok(Err(output)), _)) => {
+                    let mut message = "The browser exited before DevTools started".to_owned();
+                    if !output.is_empty() {
+                        message = format!("{}:\n{}", message, output.trim_end());
+                    }
+                    return Err(Error::Launch(message));
+                }
+                Either::Left((Err(_), _)) => unreachable!("the stderr thread always replies"),
+                Either::Right(_) => {
+                    let message = format!(
+                        "DevTools didn't start listening within {:?}",
+                        options.startup_timeout
+                    );
+                    return Err(Error::Launch(message));
+                }
+            };
+            browser.websocket_url = Some(websocket_url);
+            Ok(browser)
+        }
+    }
+
+    /// The WebSocket URL of the browser target. `None` with pipes.
+    pub fn websocket_url(&self) -> Option<&Url> {
+        self.websocket_url.as_ref()
+    }
+
+    /// The HTTP endpoints of the browser. `None` with pipes.
+    pub fn endpoints(&self) -> Option<Endpoints> {
+        let url = self.websocket_url.as_ref()?;
+        let host = url.host_str().unwrap_or("localhost");
+        let port = url.port_or_known_default().unwrap_or(80);
+        Some(Endpoints::new(host, port, None))
+    }
+
+    /// Takes the pipes to a browser launched with `LaunchOptions::pipe`.
+    pub fn take_pipe(&mut self) -> Option<(pipe::Sender, pipe::Receiver)> {
+        self.pipe.take()
+    }
+
+    /// Connects to the browser target, over the pipes if the browser was
+    /// launched with them, and spawns the connection's driver. The pipes can
+    /// only be used by one connection.
+    #[cfg(any(feature = "async-std", feature = "smol", feature = "tokio"))]
+    pub fn connect(
+        &mut self,
+        config: &websocket::Config,
+    ) -> impl Future<Output = Result<WebSocketTarget, Error>> {
+        let connect = self.connect_with_driver(config);
+        async move {
+            let (target, driver) = connect.await?;
+            runtime::spawn(driver);
+            Ok(target)
+        }
+    }
+
+    /// Like `connect`, but the caller must drive the returned `Driver`.
+    pub fn connect_with_driver(
+        &mut self,
+        config: &websocket::Config,
+    ) -> impl Future<Output = Result<(WebSocketTarget, Driver), Error>> {
+        let pipe = self.pipe.take();
+        let url = self.websocket_url.clone();
+        let config = config.clone();
+        async move {
+            match (pipe, url) {
+                (Some(pipe), _) => Ok(WebSocketTarget::new(pipe)),
+                (None, Some(url)) => WebSocketTarget::connect_with_driver(url, &config).await,
+                (None, None) => Err(Error::Disconnected(
+                    "The pipes are already in use".to_owned(),
+                )),
+            }
+        }
+    }
+
+    /// The process ID of the browser.
+    pub fn id(&self) -> u32 {
+        self.child.id()
+    }
+}
+
+impl Drop for Browser {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        if let Some(ref dir) = self.temp_dir {
+            let _ = fs::remove_dir_all(dir);
+        }
+    }
+}
+
+/// Makes the child read messages from descriptor 3 and write replies to 4.
+/// Returns the parent's ends of the pipes.
+#[cfg(unix)]
+fn pass_pipes(command: &mut Command) -> Result<(io::PipeReader, io::PipeWriter), Error> {
+    use std::os::unix::io::AsRawFd;
+
+    let (child_reader, writer) = io::pipe()?;
+    let (reader, child_writer) = io::pipe()?;
+    let fds = [child_reader.as_raw_fd(), child_writer.as_raw_fd()];
+    // The child's ends are closed in the parent once the command is dropped.
+    dup_to_3_and_4(command, fds, (child_reader, child_writer));
+    Ok((reader, writer))
+}
+
+/// Makes the child's descriptors 3 and 4 copies of `fds`. `keep` holds
+/// `fds` open until the command is dropped.
+#[cfg(unix)]
+fn dup_to_3_and_4(
+    command: &mut Command,
+    fds: [std::os::unix::io::RawFd; 2],
+    keep: impl Send + Sync + 'static,
+) {
+    use std::os::unix::process::CommandExt;
+
+    let pre_exec = move || {
+        let _ = &keep;
+        // Move both out of the way first, in case either is 3 or 4 already.
+        let mut high = [0; 2];
+        for (high, fd) in high.iter_mut().zip(fds.iter()) {
+            *high = unsafe { libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, 10) };
+            if *high < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        // The duplicates from `dup2` don't close on exec.
+        for (target, high) in [3, 4].iter().zip(high.iter()) {
+            if unsafe { libc::dup2(*high, *target) } < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    };
+    // Only async-signal-safe calls are made between fork and exec.
+    unsafe { command.pre_exec(pre_exec) };
+}
+
+#[cfg(not(unix))]
+fn pass_pipes(_command: &mut Command) -> Result<(io::PipeReader, io::PipeWriter), Error> {
+    Err(Error::Launch("Pipes are only supported on Unix".to_owned()))
+}
+
+/// Creates an empty directory for a temporary profile.
+fn create_temp_dir() -> Result<PathBuf, Error> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    loop {
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let name = format!("cdp-rs-profile-{}-{}", std::process::id(), n);
+        let dir = env::temp_dir().join(name);
+        match fs::create_dir(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// Reads the browser's stderr on a thread until DevTools reports its URL.
+/// Resolves to the URL, or to what the browser printed if it exited first.
+/// The thread keeps draining stderr afterwards so that the browser never
+/// blocks on a full pipe.
+fn watch_stderr(stderr: impl io::Read + Send + 'static) -> oneshot::Receiver<Result<Url, String>> {
+    let (tx, rx) = oneshot::channel();
+    thread::spawn(move || {
+        let mut tx = Some(tx);
+        let mut output = String::new();
+        for line in BufReader::new(stderr).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            if tx.is_none() {
+                continue;
+            }
+            match parse_listening_line(&line) {
+                Some(url) => {
+                    let _ = tx.take().unwrap().send(Ok(url));
+                }
+                None => {
+                    output.push_str(&line);
+                    output.push('\n');
+                }
+            }
+        }
+        if let Some(tx) = tx {
+            let _ = tx.send(Err(output));
+        }
+    });
+    rx
+}
+
+/// Parses `DevTools listening on ws://...`.
+fn parse_listening_line(line: &str) -> Option<Url> {
+    let url = line.trim().strip_prefix(LISTENING_PREFIX)?;
+    Url::parse(url).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listening_line() {
+        let url = parse_listening_line(
+            "DevTools listening on ws://127.0.0.1:35719/devtools/browser/9b1c4f1e\n",
+        );
+        assert_eq!(
+            url.unwrap().as_str(),
+            "ws://127.0.0.1:35719/devtools/browser/9b1c4f1e"
+        );
+        assert_eq!(parse_listening_line("[0101/000000.0:ERROR] nope"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn launch() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // A fake browser that checks its arguments and reports a URL.
+        let dir = create_temp_dir().unwrap();
+        let executable = dir.join("chrome");
+        let script = "#!/bin/sh\n\
+                      echo \"args: $*\" >&2\n\
+                      case \"$*\" in *--remote-debugging-port=0*--headless*--lang=en*) ;; *) exit 1;; esac\n\
+                      echo 'DevTools listening on ws://127.0.0.1:9/devtools/browser/B' >&2\n\
+                      exec sleep 30\n";
+        fs::write(&executable, script).unwrap();
+        fs::set_permissions(&executable, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let options = LaunchOptions {
+            executable: Some(executable.clone()),
+            args: vec!["--lang=en".to_owned()],
+            ..LaunchOptions::default()
+        };
+        let browser = smol::run(Browser::launch(&options)).unwrap();
+        assert_eq!(
+            browser.websocket_url().unwrap().as_str(),
+            "ws://127.0.0.1:9/devtools/browser/B"
+        );
+        let profile = browser.temp_dir.clone().unwrap();
+        assert!(profile.is_dir());
+        drop(browser);
+        assert!(!profile.exists());
+
+        // The browser exits without listening.
+        let options = LaunchOptions {
+            executable: Some(executable),
+            ..LaunchOptions::default()
+        };
+        match smol::run(Browser::launch(&options)) {
+            Err(Error::Launch(message)) => assert!(message.contains("args: "), "{}", message),
+            res => panic!("unexpected {:?}", res),
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn launch_with_pipes() {
+        use crate::MethodCall;
+        use std::os::unix::fs::PermissionsExt;
+
+        // A fake browser that echoes messages, so that each call gets a reply
+        // with its own id.
+        let dir = create_temp_dir().unwrap();
+        let executable = dir.join("chrome");
+        let script = "#!/bin/sh
+                      case \"$1\" in --remote-debugging-pipe) ;; *) exit 1;; esac
+                      exec cat <&3 >&4
+";
+        fs::write(&executable, script).unwrap();
+        fs::set_permissions(&executable, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let options = LaunchOptions {
+            executable: Some(executable),
+            pipe: true,
+            ..LaunchOptions::default()
+        };
+        smol::run(async {
+            let mut browser = Browser::launch(&options).await.unwrap();
+            assert!(browser.websocket_url().is_none());
+            let (target, driver) = browser
+                .connect_with_driver(&websocket::Config::default())
+                .await
+                .unwrap();
+            smol::Task::spawn(driver).detach();
+            let method = MethodCall::new("Browser.getVersion", serde_json::json!({}));
+            let result = target.call_method(&method).await.unwrap();
+            assert_eq!(result, serde_json::Value::Null);
+            assert!(browser
+                .connect_with_driver(&websocket::Config::default())
+                .await
+                .is_err());
+
+            // Closing the pipe ends the fake browser.
+            target.close().await.unwrap();
+        });
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pipes_already_on_3_and_4() {
+        use std::io::{Read, Write};
+        use std::os::unix::io::AsRawFd;
+        use std::os::unix::process::CommandExt;
+
+        let (child_reader, mut writer) = io::pipe().unwrap();
+        let (mut reader, child_writer) = io::pipe().unwrap();
+        let fds = [child_reader.as_raw_fd(), child_writer.as_raw_fd()];
+        let mut command = Command::new("sh");
+        command.args(["-c", "cat <&3 >&4"]);
+        // Take 3 and 4 in the child the other way around before the pipes
+        // are passed.
+        let take_3_and_4 = move || {
+            for (fd, target) in fds.iter().zip([4, 3].iter()) {
+                let high = unsafe { libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, 10) };
+                if high < 0 || unsafe { libc::dup2(high, *target) } < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        };
+        unsafe { command.pre_exec(take_3_and_4) };
+        dup_to_3_and_4(&mut command, [4, 3], (child_reader, child_writer));
+        let mut child = command.spawn().unwrap();
+        drop(command);
+
+        writer.write_all(b"hello").unwrap();
+        drop(writer);
+        let mut output = String::new();
+        reader.read_to_string(&mut output).unwrap();
+        assert!(child.wait().unwrap().success());
+        assert_eq!(output, "hello");
+    }
+}
@@ -0,0 +1,43 @@
+//! A client for the Chrome DevTools Protocol.
+//!
+//! [`Endpoints`] talks to the HTTP endpoints of a browser to list, open and
+//! close targets. [`WebSocketTarget`] connects to a page or to the browser
+//! itself, and [`Session`]s drive other targets over a browser connection.
+//! [`Browser`] starts a browser to connect to. Connections run over a
+//! [`Transport`], which tests can replace with an in-memory one.
+//!
+//! The crate works with any async runtime. Each connection has a [`Driver`]
+//! future that must be polled; the `tokio`, `async-std` and `smol` features
+//! let constructors spawn it.
+//!
+//! The default `cli` feature only exists for the `cdp-cli` binary. Depend on
+//! the crate with `default-features = false` to leave out its dependencies.
+
+mod deflate;
+pub mod discovery;
+pub mod endpoints;
+mod error;
+pub mod launcher;
+mod parser;
+pub mod pipe;
+#[cfg(feature = "protocol")]
+pub mod protocol;
+#[cfg(any(feature = "async-std", feature = "smol", feature = "tokio"))]
+mod runtime;
+pub mod schema;
+mod tls;
+pub mod transport;
+pub mod websocket;
+pub mod websocket_target;
+
+pub use crate::deflate::DeflateConfig;
+pub use crate::endpoints::{BrowserVersionMetadata, Endpoints, TargetItem};
+pub use crate::error::Error;
+pub use crate::launcher::{Browser, LaunchOptions};
+pub use crate::parser::{ParseError, Predicate};
+pub use crate::tls::TlsConfig;
+pub use crate::transport::Transport;
+pub use crate::websocket_target::{
+    Closed, Driver, Event, EventFilter, EventStream, MethodCall, MethodError, Session,
+    WebSocketTarget,
+};
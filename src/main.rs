@@ -1,25 +1,137 @@
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::process;
+
 use structopt::StructOpt;
 
+use cdp_rs::EventFilter;
+
 mod cli;
-mod endpoints;
-mod websocket;
-mod websocket_target;
+mod completion;
 
 #[derive(Debug, StructOpt)]
 #[structopt(
     name = "cdp-cli",
-    about = "A commandline tool for Chrome DevTools Protocol"
+    about = "A commandline tool for Chrome DevTools Protocol",
+    after_help = "Without a subcommand, starts a REPL. Subcommands print JSON to stdout and \
+                  exit with 1 if they fail, or with 2 if a method call is invalid."
 )]
 struct Opt {
     #[structopt(long, default_value = "localhost")]
     host: String,
     #[structopt(long, default_value = "9222")]
     port: u16,
+    /// Start a browser with a temporary profile instead of connecting to
+    /// --host and --port, and kill it on exit
+    #[structopt(long)]
+    launch: bool,
+    /// Browser to start with --launch; found in CHROME_PATH, PATH or the
+    /// usual install locations by default
+    #[structopt(long, parse(from_os_str))]
+    chrome: Option<PathBuf>,
+    /// Talk to the browser started with --launch over pipes instead of a
+    /// TCP port
+    #[structopt(long, requires = "launch")]
+    pipe: bool,
+    /// Start the browser without a window
+    #[structopt(long)]
+    headless: bool,
+    /// Connect to the browser using this profile, on the port in its
+    /// DevToolsActivePort file; with --launch, start the browser with it
+    #[structopt(long, parse(from_os_str))]
+    user_data_dir: Option<PathBuf>,
+    /// Extra argument for the browser started with --launch; may be repeated
+    #[structopt(long = "chrome-arg", number_of_values = 1, allow_hyphen_values = true)]
+    chrome_args: Vec<String>,
+    /// Connect to the browser and attach to targets over a single socket
+    #[structopt(long)]
+    browser: bool,
+    /// Use HTTPS for endpoints and WSS for targets
+    #[structopt(long)]
+    https: bool,
+    /// Trust CA certificates in this PEM file
+    #[structopt(long, parse(from_os_str))]
+    ca_file: Option<PathBuf>,
+    /// Skip TLS certificate verification
+    #[structopt(long)]
+    insecure: bool,
+    /// Write events to this file
+    #[structopt(long, parse(from_os_str))]
+    events_log: Option<PathBuf>,
+    /// Events to write: `*`, `Domain` or `Domain.event`
+    #[structopt(long, default_value = "*")]
+    events_filter: EventFilter,
+    /// Maximum size of an incoming message in bytes
+    #[structopt(long)]
+    max_message_size: Option<usize>,
+    /// Split outgoing messages into frames of at most this many bytes
+    #[structopt(long)]
+    max_frame_size: Option<usize>,
+    /// Send a ping every this many seconds
+    #[structopt(long)]
+    keepalive_interval: Option<u64>,
+    /// Give up on a method call after this many seconds; 0 waits forever
+    #[structopt(long, default_value = "30")]
+    timeout: u64,
+    /// Don't negotiate permessage-deflate
+    #[structopt(long)]
+    no_compression: bool,
+    /// Send method calls without checking them against the protocol schema
+    #[structopt(long)]
+    no_validate: bool,
+    /// Run a single command instead of the REPL
+    #[structopt(subcommand)]
+    command: Option<Subcommand>,
+}
+
+/// Commands that run once instead of the REPL.
+#[derive(Debug, StructOpt)]
+enum Subcommand {
+    /// Call a method, e.g. `Page.navigate({url: 'about:blank'})`
+    Call { method: String },
+    /// List targets
+    List,
+    /// Show the browser version
+    Version,
+    /// Open a new tab
+    New { url: String },
+    /// Run the REPL commands in a file, stopping at the first that fails
+    Run {
+        #[structopt(parse(from_os_str))]
+        script: PathBuf,
+    },
+    /// List browsers found in default profiles and on a range of ports of
+    /// --host
+    Discover {
+        /// Ports to scan, e.g. `9222-9322` or `9222`
+        #[structopt(long, default_value = "9222-9322", parse(try_from_str = parse_port_range))]
+        ports: RangeInclusive<u16>,
+    },
 }
 
-type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+fn parse_port_range(s: &str) -> Result<RangeInclusive<u16>, String> {
+    let invalid = |_| format!("invalid port range: {}", s);
+    match s.find('-') {
+        Some(dash) => {
+            let start = s[..dash].trim().parse().map_err(invalid)?;
+            let end = s[dash + 1..].trim().parse().map_err(invalid)?;
+            Ok(start..=end)
+        }
+        None => {
+            let port = s.trim().parse().map_err(invalid)?;
+            Ok(port..=port)
+        }
+    }
+}
 
-fn main() -> Result<(), Error> {
-    let opt = Opt::from_args();
-    smol::run(cli::run_repl(opt))
+fn main() {
+    let mut opt = Opt::from_args();
+    let result = match opt.command.take() {
+        Some(command) => smol::run(cli::run_command(command, opt)),
+        None => smol::run(cli::run_repl(opt)).map(|()| 0),
+    };
+    let code = cli::exit_code(result);
+    if code != 0 {
+        process::exit(code);
+    }
 }
@@ -0,0 +1,757 @@
+//! Parser for method calls typed by hand, e.g. `Page.navigate({url: 'about:blank'})`.
+//!
+//! Parameters are relaxed JSON in the style of JSON5: keys may be unquoted,
+//! strings may use single quotes, and arrays and objects may end with a
+//! trailing comma. A value may also refer to a variable or a part of it, e.g.
+//! `$doc.root.children[0].nodeId`.
+//!
+//! [`Predicate`]s reuse the value syntax to describe conditions on events.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use serde_json::{Map, Number, Value};
+
+/// Why a method call couldn't be parsed, and where.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    /// 1-based line of the offending character.
+    pub line: usize,
+    /// 1-based column of the offending character, counted in characters.
+    pub column: usize,
+    pub message: String,
+    incomplete: bool,
+}
+
+impl ParseError {
+    /// Whether the input ended inside brackets, so more input could complete
+    /// it.
+    pub fn is_incomplete(&self) -> bool {
+        self.incomplete
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} at line {}, column {}",
+            self.message, self.line, self.column
+        )
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Parses `Domain.method`, optionally followed by parameters in parentheses.
+/// Returns the method name and the parameters, `{}` if there are none.
+///
+/// References are replaced with values from `variables`. Without variables,
+/// references are only checked for syntax and become `null`.
+pub(crate) fn parse_method_call(
+    input: &str,
+    variables: Option<&HashMap<String, Value>>,
+) -> Result<(String, Value), ParseError> {
+    let mut parser = Parser::new(input, variables);
+    parser.skip_whitespace();
+    let domain = parser.identifier("domain")?;
+    parser.expect('.')?;
+    let name = parser.identifier("method name")?;
+    parser.skip_whitespace();
+
+    let mut params = Value::Object(Map::new());
+    if parser.eat('(') {
+        parser.depth += 1;
+        parser.skip_whitespace();
+        if parser.peek() != Some(')') {
+            let start = parser.pos;
+            params = parser.value()?;
+            if !params.is_object() {
+                return Err(parser.error_at(start, "Parameters must be an object"));
+            }
+            parser.skip_whitespace();
+        }
+        parser.expect(')')?;
+        parser.depth -= 1;
+        parser.skip_whitespace();
+    }
+    if let Some(c) = parser.peek() {
+        return Err(parser.error(format!("Unexpected {:?} after the method call", c)));
+    }
+    Ok((format!("{}.{}", domain, name), params))
+}
+
+/// Returns the keys of the object that starts `input`, as far as it can be
+/// parsed. Used to tell which parameters a call being typed already has.
+pub(crate) fn object_keys(input: &str) -> Vec<String> {
+    let mut parser = Parser::new(input, None);
+    let mut keys = Vec::new();
+    parser.skip_whitespace();
+    if !parser.eat('{') {
+        return keys;
+    }
+    loop {
+        parser.skip_whitespace();
+        let key = match parser.key() {
+            Ok(key) => key,
+            Err(_) => break,
+        };
+        parser.skip_whitespace();
+        if !parser.eat(':') {
+            break;
+        }
+        parser.skip_whitespace();
+        if parser.value().is_err() {
+            break;
+        }
+        keys.push(key);
+        parser.skip_whitespace();
+        if !parser.eat(',') {
+            break;
+        }
+    }
+    keys
+}
+
+/// A condition on a JSON value, e.g. the parameters of an event.
+///
+/// It is written as a path into the value, optionally compared to another
+/// value: `frameId == 'F1'`, `response.status != 200` or `args[0].value`.
+/// The path may start with `$`, as in `$.frameId`. Without a comparison, the
+/// condition holds if the path leads to a value other than `null` or `false`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Predicate {
+    path: Vec<PathPart>,
+    comparison: Comparison,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Comparison {
+    Present,
+    Equal(Value),
+    NotEqual(Value),
+}
+
+impl Predicate {
+    pub fn parse(s: &str) -> Result<Self, ParseError> {
+        Predicate::parse_with_variables(s, &HashMap::new())
+    }
+
+    /// Like `parse`, but the value compared to may refer to `variables`, e.g.
+    /// `frameId == $nav.frameId`.
+    pub fn parse_with_variables(
+        s: &str,
+        variables: &HashMap<String, Value>,
+    ) -> Result<Self, ParseError> {
+        let mut parser = Parser::new(s, Some(variables));
+        parser.skip_whitespace();
+        let mut path = Vec::new();
+        if !parser.eat('$') {
+            path.push(PathPart::Field(parser.identifier("a field name")?));
+        }
+        while let Some(part) = parser.path_part()? {
+            path.push(part);
+        }
+        parser.skip_whitespace();
+
+        let comparison = if parser.peek().is_none() {
+            Comparison::Present
+        } else {
+            let equal = if parser.eat('=') {
+                true
+            } else if parser.eat('!') {
+                false
+            } else {
+                return Err(parser.unexpected("'==' or '!='"));
+            };
+            parser.expect('=')?;
+            parser.skip_whitespace();
+            let value = parser.value()?;
+            parser.skip_whitespace();
+            if let Some(c) = parser.peek() {
+                return Err(parser.error(format!("Unexpected {:?} after the value", c)));
+            }
+            if equal {
+                Comparison::Equal(value)
+            } else {
+                Comparison::NotEqual(value)
+            }
+        };
+        Ok(Predicate { path, comparison })
+    }
+
+    /// Whether the condition holds for `value`. A path that leads nowhere
+    /// counts as `null`.
+    pub fn matches(&self, value: &Value) -> bool {
+        let found = self
+            .path
+            .iter()
+            .try_fold(value, |value, part| part.get(value));
+        let found = found.unwrap_or(&Value::Null);
+        match self.comparison {
+            Comparison::Present => !matches!(found, Value::Null | Value::Bool(false)),
+            Comparison::Equal(ref expected) => json_eq(found, expected),
+            Comparison::NotEqual(ref expected) => !json_eq(found, expected),
+        }
+    }
+}
+
+/// Compares numbers by value, so that `1` equals `1.0`.
+fn json_eq(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64() == b.as_f64(),
+        _ => a == b,
+    }
+}
+
+/// A step of a path into a JSON value.
+#[derive(Debug, Clone, PartialEq)]
+enum PathPart {
+    Field(String),
+    Index(usize),
+}
+
+impl PathPart {
+    fn get<'v>(&self, value: &'v Value) -> Option<&'v Value> {
+        match self {
+            PathPart::Field(field) => value.get(field),
+            PathPart::Index(index) => value.get(index),
+        }
+    }
+}
+
+impl fmt::Display for PathPart {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PathPart::Field(field) => write!(f, "{:?}", field),
+            PathPart::Index(index) => write!(f, "{}", index),
+        }
+    }
+}
+
+struct Parser<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    /// Number of brackets open at `pos`.
+    depth: usize,
+    variables: Option<&'a HashMap<String, Value>>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(input: &str, variables: Option<&'a HashMap<String, Value>>) -> Self {
+        Parser {
+            chars: input.chars().collect(),
+            pos: 0,
+            depth: 0,
+            variables,
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        Some(c)
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), ParseError> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(self.unexpected(&format!("'{}'", c)))
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn error(&self, message: impl Into<String>) -> ParseError {
+        self.error_at(self.pos, message)
+    }
+
+    fn error_at(&self, pos: usize, message: impl Into<String>) -> ParseError {
+        let before = &self.chars[..pos];
+        let line = before.iter().filter(|c| **c == '\n').count() + 1;
+        let line_start = before.iter().rposition(|c| *c == '\n').map_or(0, |i| i + 1);
+        ParseError {
+            line,
+            column: pos - line_start + 1,
+            message: message.into(),
+            incomplete: pos == self.chars.len() && self.depth > 0,
+        }
+    }
+
+    fn unexpected(&self, expected: &str) -> ParseError {
+        match self.peek() {
+            Some(c) => self.error(format!("Expected {}, found {:?}", expected, c)),
+            None => self.error(format!("Expected {}, found end of input", expected)),
+        }
+    }
+
+    fn identifier(&mut self, what: &str) -> Result<String, ParseError> {
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$')
+        {
+            self.pos += 1;
+        }
+        if self.pos == start || self.chars[start].is_ascii_digit() {
+            self.pos = start;
+            return Err(self.unexpected(what));
+        }
+        Ok(self.chars[start..self.pos].iter().collect())
+    }
+
+    fn value(&mut self) -> Result<Value, ParseError> {
+        match self.peek() {
+            Some('{') => self.object(),
+            Some('[') => self.array(),
+            Some('"') | Some('\'') => self.string().map(Value::String),
+            Some('$') => self.reference(),
+            Some(c) if c == '-' || c == '+' || c == '.' || c.is_ascii_digit() => self.number(),
+            Some(c) if c.is_ascii_alphabetic() => {
+                let start = self.pos;
+                let word = self.identifier("a value")?;
+                match word.as_str() {
+                    "true" => Ok(Value::Bool(true)),
+                    "false" => Ok(Value::Bool(false)),
+                    "null" => Ok(Value::Null),
+                    _ => Err(self.error_at(start, format!("Unexpected word {:?}", word))),
+                }
+            }
+            _ => Err(self.unexpected("a value")),
+        }
+    }
+
+    /// Parses `$name` followed by any number of `.field` and `[index]`.
+    fn reference(&mut self) -> Result<Value, ParseError> {
+        let start = self.pos;
+        self.expect('$')?;
+        let name = self.identifier("a variable name")?;
+        let mut value = match self.variables {
+            Some(variables) => match variables.get(&name) {
+                Some(value) => Some(value),
+                None => return Err(self.error_at(start, format!("Undefined variable ${}", name))),
+            },
+            None => None,
+        };
+        loop {
+            let part_start = self.pos;
+            let part = match self.path_part()? {
+                Some(part) => part,
+                None => break,
+            };
+            value = match value {
+                Some(value) => {
+                    let item = part.get(value);
+                    if item.is_none() {
+                        let path: String = self.chars[start..part_start].iter().collect();
+                        let message = format!("{} has no {}", path, part);
+                        return Err(self.error_at(part_start, message));
+                    }
+                    item
+                }
+                None => None,
+            };
+        }
+        Ok(value.cloned().unwrap_or(Value::Null))
+    }
+
+    /// Parses `.field` or `[index]`, if there is one.
+    fn path_part(&mut self) -> Result<Option<PathPart>, ParseError> {
+        if self.eat('.') {
+            let field = self.identifier("a field name")?;
+            Ok(Some(PathPart::Field(field)))
+        } else if self.eat('[') {
+            let start = self.pos;
+            let index = self.number()?;
+            let index = match index.as_u64() {
+                Some(index) => index as usize,
+                None => return Err(self.error_at(start, format!("Invalid index {}", index))),
+            };
+            self.expect(']')?;
+            Ok(Some(PathPart::Index(index)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn object(&mut self) -> Result<Value, ParseError> {
+        self.expect('{')?;
+        self.depth += 1;
+        let mut map = Map::new();
+        loop {
+            self.skip_whitespace();
+            if self.eat('}') {
+                break;
+            }
+            let key = self.key()?;
+            self.skip_whitespace();
+            self.expect(':')?;
+            self.skip_whitespace();
+            let value = self.value()?;
+            map.insert(key, value);
+            self.skip_whitespace();
+            if self.eat('}') {
+                break;
+            }
+            if !self.eat(',') {
+                return Err(self.unexpected("',' or '}'"));
+            }
+        }
+        self.depth -= 1;
+        Ok(Value::Object(map))
+    }
+
+    /// Parses an object key, quoted or not.
+    fn key(&mut self) -> Result<String, ParseError> {
+        match self.peek() {
+            Some('"') | Some('\'') => self.string(),
+            _ => self.identifier("a key or '}'"),
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, ParseError> {
+        self.expect('[')?;
+        self.depth += 1;
+        let mut items = Vec::new();
+        loop {
+            self.skip_whitespace();
+            if self.eat(']') {
+                break;
+            }
+            items.push(self.value()?);
+            self.skip_whitespace();
+            if self.eat(']') {
+                break;
+            }
+            if !self.eat(',') {
+                return Err(self.unexpected("',' or ']'"));
+            }
+        }
+        self.depth -= 1;
+        Ok(Value::Array(items))
+    }
+
+    fn string(&mut self) -> Result<String, ParseError> {
+        let quote = self.bump().unwrap();
+        let mut s = String::new();
+        loop {
+            let c = match self.bump() {
+                Some(c) => c,
+                None => return Err(self.unterminated_string()),
+            };
+            match c {
+                c if c == quote => break,
+                '\n' => {
+                    self.pos -= 1;
+                    return Err(self.unterminated_string());
+                }
+                '\\' => s.push(self.escape()?),
+                c => s.push(c),
+            }
+        }
+        Ok(s)
+    }
+
+    fn unterminated_string(&self) -> ParseError {
+        // Strings can't span lines, so more input wouldn't help.
+        ParseError {
+            incomplete: false,
+            ..self.error("Unterminated string")
+        }
+    }
+
+    fn escape(&mut self) -> Result<char, ParseError> {
+        let c = match self.bump() {
+            Some(c) => c,
+            None => return Err(self.unterminated_string()),
+        };
+        let c = match c {
+            'b' => '\u{8}',
+            'f' => '\u{c}',
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            'u' => {
+                let start = self.pos - 2;
+                let high = self.hex4()?;
+                let code = if (0xd800..0xdc00).contains(&high) {
+                    // A surrogate pair.
+                    if !(self.eat('\\') && self.eat('u')) {
+                        return Err(self.error_at(start, "Unpaired surrogate"));
+                    }
+                    let low = self.hex4()?;
+                    if !(0xdc00..0xe000).contains(&low) {
+                        return Err(self.error_at(start, "Unpaired surrogate"));
+                    }
+                    0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+                } else {
+                    high
+                };
+                return std::char::from_u32(code)
+                    .ok_or_else(|| self.error_at(start, "Unpaired surrogate"));
+            }
+            '"' | '\'' | '\\' | '/' => c,
+            _ => {
+                // Point at the backslash.
+                self.pos -= 2;
+                return Err(self.error(format!("Invalid escape {:?}", c)));
+            }
+        };
+        Ok(c)
+    }
+
+    fn hex4(&mut self) -> Result<u32, ParseError> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let digit = self.peek().and_then(|c| c.to_digit(16));
+            match digit {
+                Some(digit) => code = code * 16 + digit,
+                None => return Err(self.unexpected("a hex digit")),
+            }
+            self.pos += 1;
+        }
+        Ok(code)
+    }
+
+    fn number(&mut self) -> Result<Value, ParseError> {
+        let start = self.pos;
+        if !self.eat('+') {
+            self.eat('-');
+        }
+        let mut is_float = false;
+        while let Some(c) = self.peek() {
+            match c {
+                '0'..='9' => (),
+                '.' | 'e' | 'E' => is_float = true,
+                '+' | '-' if matches!(self.chars[self.pos - 1], 'e' | 'E') => (),
+                _ => break,
+            }
+            self.pos += 1;
+        }
+        let text: String = self.chars[start..self.pos].iter().collect();
+        let text = text.trim_start_matches('+');
+        let invalid = || self.error_at(start, format!("Invalid number {:?}", text));
+        if is_float {
+            // Also takes a leading or trailing decimal point, as JSON5 does.
+            let n: f64 = text.parse().map_err(|_| invalid())?;
+            Number::from_f64(n).map(Value::Number).ok_or_else(invalid)
+        } else if let Ok(n) = text.parse::<i64>() {
+            Ok(Value::Number(n.into()))
+        } else if let Ok(n) = text.parse::<u64>() {
+            Ok(Value::Number(n.into()))
+        } else {
+            Err(invalid())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn parse(input: &str) -> Result<(String, Value), ParseError> {
+        parse_method_call(input, Some(&HashMap::new()))
+    }
+
+    fn error(input: &str) -> (usize, usize, String) {
+        let err = parse(input).unwrap_err();
+        (err.line, err.column, err.message)
+    }
+
+    #[test]
+    fn method_calls() {
+        let empty = ("Page.enable".to_owned(), json!({}));
+        assert_eq!(parse("Page.enable").unwrap(), empty);
+        assert_eq!(parse("Page.enable()").unwrap(), empty);
+        assert_eq!(parse("  Page.enable ( ) ").unwrap(), empty);
+
+        let (method, params) = parse(r#"Page.navigate({"url": "about:blank"})"#).unwrap();
+        assert_eq!(method, "Page.navigate");
+        assert_eq!(params, json!({"url": "about:blank"}));
+    }
+
+    #[test]
+    fn relaxed_json() {
+        let input = "Runtime.evaluate({
+            expression: 'document.title',
+            returnByValue: true,
+            'timeout': 1.5e3,
+            args: [1, -2, +3, .5, 18446744073709551615, null, 'it\\'s', \"\\u00e9\\ud83d\\ude00\",],
+        })";
+        let (_, params) = parse(input).unwrap();
+        assert_eq!(
+            params,
+            json!({
+                "expression": "document.title",
+                "returnByValue": true,
+                "timeout": 1500.0,
+                "args": [1, -2, 3, 0.5, 18446744073709551615u64, null, "it's", "é😀"],
+            })
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            error("Page"),
+            (1, 5, "Expected '.', found end of input".to_owned())
+        );
+        assert_eq!(
+            error("Page.navigate({url 'a'})"),
+            (1, 20, "Expected ':', found '\\''".to_owned())
+        );
+        assert_eq!(
+            error("Page.navigate([1])"),
+            (1, 15, "Parameters must be an object".to_owned())
+        );
+        assert_eq!(
+            error("Page.navigate({url: 'a'}) x"),
+            (1, 27, "Unexpected 'x' after the method call".to_owned())
+        );
+        assert_eq!(
+            error("Page.navigate({url: nope})"),
+            (1, 21, "Unexpected word \"nope\"".to_owned())
+        );
+        assert_eq!(
+            error("Page.navigate({\n  url: 'a\n})"),
+            (2, 10, "Unterminated string".to_owned())
+        );
+        assert_eq!(
+            error("Page.navigate({url: '\\x'})"),
+            (1, 22, "Invalid escape 'x'".to_owned())
+        );
+        assert_eq!(
+            error("Page.navigate({n: 1.2.3})"),
+            (1, 19, "Invalid number \"1.2.3\"".to_owned())
+        );
+    }
+
+    #[test]
+    fn references() {
+        let mut variables = HashMap::new();
+        variables.insert(
+            "doc".to_owned(),
+            json!({"root": {"nodeId": 1, "children": [{"nodeId": 2}]}}),
+        );
+        let input = "DOM.describeNode({nodeId: $doc.root.children[0].nodeId, node: $doc.root})";
+        let (_, params) = parse_method_call(input, Some(&variables)).unwrap();
+        assert_eq!(
+            params,
+            json!({"nodeId": 2, "node": {"nodeId": 1, "children": [{"nodeId": 2}]}})
+        );
+
+        let error = |input: &str| {
+            let err = parse_method_call(input, Some(&variables)).unwrap_err();
+            (err.column, err.message)
+        };
+        assert_eq!(
+            error("DOM.enable({a: $nope})"),
+            (16, "Undefined variable $nope".to_owned())
+        );
+        assert_eq!(
+            error("DOM.enable({a: $doc.root.nope})"),
+            (25, "$doc.root has no \"nope\"".to_owned())
+        );
+        assert_eq!(
+            error("DOM.enable({a: $doc.root.children[1]})"),
+            (34, "$doc.root.children has no 1".to_owned())
+        );
+
+        // Without variables, references are only checked for syntax.
+        let (_, params) = parse_method_call("DOM.enable({a: $x.y[0]})", None).unwrap();
+        assert_eq!(params, json!({"a": null}));
+    }
+
+    #[test]
+    fn predicates() {
+        let params = json!({
+            "frameId": "F1",
+            "response": {"status": 200, "fromDiskCache": false},
+            "args": [{"value": 1.5}],
+        });
+        let matches = |input: &str| Predicate::parse(input).unwrap().matches(&params);
+        assert!(matches("frameId == 'F1'"));
+        assert!(matches("$.frameId=='F1'"));
+        assert!(matches("response.status == 200.0"));
+        assert!(matches("response.status != 404"));
+        assert!(matches("args[0].value == 1.5"));
+        assert!(matches("response == {status: 200, fromDiskCache: false}"));
+        assert!(matches("frameId"));
+        assert!(!matches("response.fromDiskCache"));
+        assert!(!matches("loaderId"));
+        assert!(matches("loaderId == null"));
+        assert!(!matches("args[1].value == 1.5"));
+
+        let mut variables = HashMap::new();
+        variables.insert("nav".to_owned(), json!({"frameId": "F1"}));
+        let predicate = Predicate::parse_with_variables("frameId == $nav.frameId", &variables);
+        assert!(predicate.unwrap().matches(&params));
+
+        let error = |input: &str| {
+            let err = Predicate::parse(input).unwrap_err();
+            (err.column, err.message)
+        };
+        assert_eq!(
+            error("frameId = 'F1'"),
+            (10, "Expected '=', found ' '".to_owned())
+        );
+        assert_eq!(
+            error("frameId > 1"),
+            (9, "Expected '==' or '!=', found '>'".to_owned())
+        );
+        assert_eq!(error("args[-1]"), (6, "Invalid index -1".to_owned()));
+        assert_eq!(
+            error("frameId == 'F1' x"),
+            (17, "Unexpected 'x' after the value".to_owned())
+        );
+    }
+
+    #[test]
+    fn keys() {
+        assert_eq!(
+            object_keys("{url: 'a', \"referrer\": 'b', 'frameId': {x: [1]}, tr"),
+            ["url", "referrer", "frameId"]
+        );
+        assert_eq!(object_keys(" { url: $nav.url, "), ["url"]);
+        assert_eq!(object_keys("{url: 'a"), Vec::<String>::new());
+        assert_eq!(object_keys("url: 'a'"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn incomplete() {
+        for input in &[
+            "Page.navigate(",
+            "Page.navigate({url: 'a',",
+            "Page.navigate({a: [1,\n",
+        ] {
+            assert!(parse(input).unwrap_err().is_incomplete(), "{}", input);
+        }
+        for input in &[
+            "Page.",
+            "Page.navigate({url: 'a",
+            "Page.navigate({url: 'a'}",
+        ] {
+            let err = parse(input).unwrap_err();
+            assert_eq!(err.is_incomplete(), input.ends_with('}'), "{}", input);
+        }
+    }
+}
@@ -0,0 +1,198 @@
+//! DevTools over a pair of pipes, as with Chrome's `--remote-debugging-pipe`.
+//!
+//! The browser reads messages from file descriptor 3 and writes to 4. Each
+//! message is JSON followed by a NUL byte. Blocking reads and writes run on
+//! threads of their own, so the pipes work with any async runtime.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::thread;
+
+use futures::channel::{mpsc, oneshot};
+use futures::{ready, Future, Sink, Stream};
+
+use crate::websocket::Message;
+use crate::Error;
+
+/// Ends every message.
+const DELIMITER: u8 = 0;
+
+/// The sending half of a pipe connection. Closing the sink closes the pipe,
+/// which makes the browser exit.
+pub struct Sender {
+    tx: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    /// The error that stopped the writer thread, if any.
+    error: Arc<Mutex<Option<io::Error>>>,
+    /// Resolves when the writer thread has written everything and closed
+    /// the pipe.
+    done: oneshot::Receiver<()>,
+}
+
+impl Sender {
+    fn check(&self) -> Result<(), Error> {
+        match self.error.lock().unwrap().take() {
+            Some(err) => Err(err.into()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Sink<Message> for Sender {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(self.check())
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Error> {
+        let mut data = match item {
+            Message::Text(text) => text.into_bytes(),
+            Message::Binary(data) => data,
+            // Pipes have no control messages.
+            Message::Ping(_) | Message::Pong(_) => return Ok(()),
+            Message::Close(_) => {
+                self.tx = None;
+                return Ok(());
+            }
+        };
+        data.push(DELIMITER);
+        let closed = || Error::Disconnected("Pipe closed".to_owned());
+        let tx = self.tx.as_ref().ok_or_else(closed)?;
+        tx.unbounded_send(data).map_err(|_| closed())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        // Messages are handed to the writer thread as they are sent.
+        Poll::Ready(self.check())
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.tx = None;
+        let _ = ready!(Pin::new(&mut self.done).poll(cx));
+        Poll::Ready(self.check())
+    }
+}
+
+/// The receiving half of a pipe connection. The stream yields
+/// `Message::Close(None)` when the browser closes the pipe, then ends.
+pub struct Receiver {
+    rx: mpsc::UnboundedReceiver<Result<Message, Error>>,
+}
+
+impl Stream for Receiver {
+    type Item = Result<Message, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.rx).poll_next(cx)
+    }
+}
+
+/// Starts a connection that writes messages to `writer` and reads them from
+/// `reader`, e.g. the ends of the pipes to a browser's descriptors 3 and 4.
+pub fn new(
+    reader: impl Read + Send + 'static,
+    writer: impl Write + Send + 'static,
+) -> (Sender, Receiver) {
+    let (tx, rx) = mpsc::unbounded::<Vec<u8>>();
+    let error = Arc::new(Mutex::new(None));
+    let (done_tx, done_rx) = oneshot::channel();
+    let thread_error = error.clone();
+    thread::spawn(move || {
+        if let Err(err) = write_messages(rx, writer) {
+            *thread_error.lock().unwrap() = Some(err);
+        }
+        let _ = done_tx.send(());
+    });
+    let sender = Sender {
+        tx: Some(tx),
+        error,
+        done: done_rx,
+    };
+
+    let (tx, rx) = mpsc::unbounded();
+    thread::spawn(move || read_messages(reader, tx));
+    (sender, Receiver { rx })
+}
+
+fn write_messages(
+    rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    mut writer: impl Write,
+) -> Result<(), io::Error> {
+    for data in futures::executor::block_on_stream(rx) {
+        writer.write_all(&data)?;
+        writer.flush()?;
+    }
+    // Dropping the writer closes the pipe.
+    Ok(())
+}
+
+fn read_messages(reader: impl Read, tx: mpsc::UnboundedSender<Result<Message, Error>>) {
+    let mut reader = BufReader::new(reader);
+    loop {
+        let mut data = Vec::new();
+        let message = match reader.read_until(DELIMITER, &mut data) {
+            Ok(0) => Ok(Message::Close(None)),
+            Ok(_) if data.last() != Some(&DELIMITER) => {
+                Err(io::Error::from(io::ErrorKind::UnexpectedEof).into())
+            }
+            Ok(_) => {
+                data.pop();
+                String::from_utf8(data)
+                    .map(Message::Text)
+                    .map_err(|_| Error::InvalidResponse("Invalid UTF-8 in a message".to_owned()))
+            }
+            Err(err) => Err(err.into()),
+        };
+        let last = !matches!(message, Ok(Message::Text(_)));
+        if tx.unbounded_send(message).is_err() || last {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{SinkExt, StreamExt};
+
+    #[test]
+    fn messages() {
+        let (browser_reader, writer) = io::pipe().unwrap();
+        let (reader, mut browser_writer) = io::pipe().unwrap();
+        let (mut sender, mut receiver) = new(reader, writer);
+
+        smol::run(async {
+            sender
+                .send(Message::Text(
+                    r#"{"id":1,"method":"Page.enable"}"#.to_owned(),
+                ))
+                .await
+                .unwrap();
+            sender.close().await.unwrap();
+            let mut sent = Vec::new();
+            BufReader::new(browser_reader)
+                .read_to_end(&mut sent)
+                .unwrap();
+            assert_eq!(sent, b"{\"id\":1,\"method\":\"Page.enable\"}\0");
+
+            browser_writer
+                .write_all(b"{\"id\":1}\0{\"id\":2}\0")
+                .unwrap();
+            drop(browser_writer);
+            let mut received = Vec::new();
+            while let Some(message) = receiver.next().await {
+                received.push(message.unwrap());
+            }
+            assert_eq!(
+                received,
+                vec![
+                    Message::Text(r#"{"id":1}"#.to_owned()),
+                    Message::Text(r#"{"id":2}"#.to_owned()),
+                    Message::Close(None),
+                ]
+            );
+        });
+    }
+}
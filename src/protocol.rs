@@ -0,0 +1,98 @@
+//! Typed bindings for every domain of the protocol, generated by `build.rs`
+//! from the schema in `protocol/`.
+//!
+//! Each command has a `...Params` struct implementing [`Command`], which
+//! ties it to its `...Returns` struct, and each event has an `...Event`
+//! struct implementing [`Event`].
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// A command with typed parameters and return value. Send it with
+/// `WebSocketTarget::call` or `Session::call`.
+pub trait Command: Serialize {
+    /// The method name, e.g. `Page.navigate`.
+    const NAME: &'static str;
+    /// The `result` object of the reply.
+    type Returns: DeserializeOwned;
+}
+
+/// The parameters of an event. See `websocket_target::Event::parse`.
+pub trait Event: DeserializeOwned {
+    /// The method name, e.g. `Page.frameNavigated`.
+    const NAME: &'static str;
+}
+
+include!(concat!(env!("OUT_DIR"), "/protocol.rs"));
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn command_params() {
+        let params = page::NavigateParams {
+            url: "https://example.com/".to_owned(),
+            referrer: None,
+            transition_type: Some(page::TransitionType::AddressBar),
+            frame_id: None,
+            referrer_policy: None,
+        };
+        assert_eq!(
+            serde_json::to_value(&params).unwrap(),
+            json!({"url": "https://example.com/", "transitionType": "address_bar"})
+        );
+        assert_eq!(<page::NavigateParams as Command>::NAME, "Page.navigate");
+
+        // Commands without required parameters can be built with `default`.
+        let params = dom::GetDocumentParams::default();
+        assert_eq!(serde_json::to_value(&params).unwrap(), json!({}));
+    }
+
+    #[test]
+    fn returns_and_recursive_types() {
+        let value = json!({
+            "root": {
+                "nodeId": 1,
+                "backendNodeId": 2,
+                "nodeType": 9,
+                "nodeName": "#document",
+                "localName": "",
+                "nodeValue": "",
+                "contentDocument": {
+                    "nodeId": 3,
+                    "backendNodeId": 4,
+                    "nodeType": 9,
+                    "nodeName": "#document",
+                    "localName": "",
+                    "nodeValue": ""
+                }
+            }
+        });
+        let returns: dom::GetDocumentReturns = serde_json::from_value(value).unwrap();
+        assert_eq!(returns.root.node_id, 1);
+        assert_eq!(returns.root.backend_node_id, 2);
+        assert_eq!(returns.root.content_document.unwrap().node_id, 3);
+    }
+
+    #[test]
+    fn unknown_enum_values() {
+        let value: page::TransitionType = serde_json::from_value(json!("typed")).unwrap();
+        assert_eq!(value, page::TransitionType::Typed);
+        let value: page::TransitionType = serde_json::from_value(json!("teleport")).unwrap();
+        assert_eq!(value, page::TransitionType::Unknown);
+        assert!(serde_json::to_value(value).is_err());
+    }
+
+    #[test]
+    fn events() {
+        let value = json!({"frameId": "F", "loaderId": "L", "name": "load", "timestamp": 1.5});
+        let event: page::LifecycleEventEvent = serde_json::from_value(value).unwrap();
+        assert_eq!(event.name, "load");
+        assert_eq!(
+            <page::LifecycleEventEvent as Event>::NAME,
+            "Page.lifecycleEvent"
+        );
+    }
+}
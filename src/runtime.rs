@@ -0,0 +1,69 @@
+//! Spawning connection drivers on the runtime picked by cargo features.
+
+use crate::Driver;
+
+/// Spawns `driver` in the background. With several runtime features, tokio
+/// is used inside a tokio runtime, and async-std or smol otherwise.
+pub(crate) fn spawn(driver: Driver) {
+    let future = async move {
+        // The error is reported by `WebSocketTarget::closed`.
+        let _ = driver.await;
+    };
+    #[cfg(feature = "tokio")]
+    {
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            handle.spawn(future);
+            return;
+        }
+    }
+    spawn_fallback(future)
+}
+
+#[cfg(feature = "async-std")]
+fn spawn_fallback(future: impl std::future::Future<Output = ()> + Send + 'static) {
+    async_std::task::spawn(future);
+}
+
+/// smol 0.3 only runs tasks inside `smol::run`.
+#[cfg(all(feature = "smol", not(feature = "async-std")))]
+fn spawn_fallback(future: impl std::future::Future<Output = ()> + Send + 'static) {
+    smol::Task::spawn(future).detach();
+}
+
+/// Panics with tokio's message about the missing runtime.
+#[cfg(not(any(feature = "async-std", feature = "smol")))]
+fn spawn_fallback(future: impl std::future::Future<Output = ()> + Send + 'static) {
+    tokio::spawn(future);
+}
+
+#[cfg(all(test, feature = "tokio"))]
+mod tests {
+    use futures::{SinkExt, StreamExt};
+    use serde_json::json;
+
+    use crate::transport::{self, Transport};
+    use crate::websocket::Message;
+    use crate::{MethodCall, WebSocketTarget};
+
+    #[test]
+    fn tokio() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let (client, browser) = transport::channel();
+        runtime.block_on(async {
+            // A browser that replies to the first call.
+            tokio::spawn(async move {
+                let (mut sender, mut receiver) = browser.split();
+                if let Some(Ok(Message::Text(text))) = receiver.next().await {
+                    let call: serde_json::Value = serde_json::from_str(&text).unwrap();
+                    let reply = json!({"id": call["id"], "result": {}});
+                    sender.send(Message::Text(reply.to_string())).await.unwrap();
+                }
+            });
+            let target = WebSocketTarget::from_transport(client);
+            let method = MethodCall::new("Page.enable", json!({}));
+            assert_eq!(target.call_method(&method).await.unwrap(), json!({}));
+        });
+    }
+}
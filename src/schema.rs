@@ -0,0 +1,562 @@
+//! A model of the protocol schema served by `/json/protocol`, and an on-disk
+//! cache for it.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use futures_lite::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::Error;
+
+/// The protocol schema of a browser.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Protocol {
+    pub version: Version,
+    pub domains: Vec<Domain>,
+}
+
+impl Protocol {
+    pub fn domain(&self, name: &str) -> Option<&Domain> {
+        self.domains.iter().find(|domain| domain.domain == name)
+    }
+
+    /// Looks up a command by its full name, e.g. `Page.navigate`.
+    pub fn command(&self, method: &str) -> Option<&Command> {
+        let (domain, name) = split_name(method)?;
+        self.domain(domain)?.command(name)
+    }
+
+    /// Looks up an event by its full name, e.g. `Page.loadEventFired`.
+    pub fn event(&self, method: &str) -> Option<&Event> {
+        let (domain, name) = split_name(method)?;
+        self.domain(domain)?.event(name)
+    }
+
+    /// Resolves a `$ref` made from within `domain`.
+    pub fn type_def(&self, domain: &str, reference: &str) -> Option<&TypeDef> {
+        let (domain, id) = split_name(reference).unwrap_or((domain, reference));
+        self.domain(domain)?.type_def(id)
+    }
+
+    /// Checks the parameters of a method call: the method must exist,
+    /// required parameters must be present, values must match their types and
+    /// no unknown parameters may be given. Returns every problem found.
+    pub fn validate(&self, method: &str, params: &Value) -> Result<(), Vec<ValidationError>> {
+        let mut errors = Vec::new();
+        let (domain, name) = split_name(method).unwrap_or((method, ""));
+        match self.domain(domain) {
+            None => errors.push(ValidationError::new(
+                "",
+                format!("Unknown domain {}", domain),
+            )),
+            Some(schema) => match schema.command(name) {
+                None => errors.push(ValidationError::new(
+                    "",
+                    format!("Unknown method {}", method),
+                )),
+                Some(command) => {
+                    self.check_properties(domain, &command.parameters, params, "", &mut errors)
+                }
+            },
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn check_properties(
+        &self,
+        domain: &str,
+        properties: &[Parameter],
+        value: &Value,
+        path: &str,
+        errors: &mut Vec<ValidationError>,
+    ) {
+        let object = match value.as_object() {
+            Some(object) => object,
+            None => {
+                errors.push(ValidationError::mismatch(path, "object", value));
+                return;
+            }
+        };
+        for property in properties {
+            let property_path = join_path(path, &property.name);
+            match object.get(&property.name) {
+                Some(value) => self.check_type(domain, &property.ty, value, &property_path, errors),
+                None if !property.optional => errors.push(ValidationError::new(
+                    property_path,
+                    "Missing required parameter",
+                )),
+                None => (),
+            }
+        }
+        for key in object.keys() {
+            if properties.iter().all(|property| &property.name != key) {
+                errors.push(ValidationError::new(
+                    join_path(path, key),
+                    "Unknown parameter",
+                ));
+            }
+        }
+    }
+
+    fn check_type(
+        &self,
+        domain: &str,
+        ty: &Type,
+        value: &Value,
+        path: &str,
+        errors: &mut Vec<ValidationError>,
+    ) {
+        if let Some(ref reference) = ty.reference {
+            // A reference to another domain is resolved within that domain.
+            let target_domain = split_name(reference).map_or(domain, |(domain, _)| domain);
+            match self.type_def(domain, reference) {
+                Some(def) => self.check_type(target_domain, &def.ty, value, path, errors),
+                None => errors.push(ValidationError::new(
+                    path,
+                    format!("Unknown type {} in the schema", reference),
+                )),
+            }
+            return;
+        }
+
+        if !ty.enum_values.is_empty() {
+            match value.as_str() {
+                Some(s) if ty.enum_values.iter().any(|value| value == s) => (),
+                Some(_) => {
+                    let values: Vec<String> = ty
+                        .enum_values
+                        .iter()
+                        .map(|value| format!("{:?}", value))
+                        .collect();
+                    let message = format!("Expected one of {}, got {}", values.join(", "), value);
+                    errors.push(ValidationError::new(path, message));
+                }
+                None => errors.push(ValidationError::mismatch(path, "string", value)),
+            }
+            return;
+        }
+
+        let kind = match ty.kind {
+            Some(kind) => kind,
+            None => return,
+        };
+        let matches = match kind {
+            TypeKind::Any => true,
+            TypeKind::Boolean => value.is_boolean(),
+            TypeKind::Integer => value.is_i64() || value.is_u64(),
+            TypeKind::Number => value.is_number(),
+            TypeKind::String => value.is_string(),
+            TypeKind::Array => match (value.as_array(), &ty.items) {
+                (Some(items), Some(item_type)) => {
+                    for (i, item) in items.iter().enumerate() {
+                        let item_path = format!("{}[{}]", path, i);
+                        self.check_type(domain, item_type, item, &item_path, errors);
+                    }
+                    true
+                }
+                (items, _) => items.is_some(),
+            },
+            TypeKind::Object if ty.properties.is_empty() => value.is_object(),
+            TypeKind::Object => {
+                self.check_properties(domain, &ty.properties, value, path, errors);
+                return;
+            }
+        };
+        if !matches {
+            let expected = format!("{:?}", kind).to_lowercase();
+            errors.push(ValidationError::mismatch(path, &expected, value));
+        }
+    }
+}
+
+fn join_path(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_owned()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+/// A problem with a method call found by `Protocol::validate`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationError {
+    /// Where the problem is, e.g. `cookies[0].name`. Empty for the method
+    /// itself.
+    pub path: String,
+    pub message: String,
+}
+
+impl ValidationError {
+    fn new(path: impl Into<String>, message: impl Into<String>) -> Self {
+        ValidationError {
+            path: path.into(),
+            message: message.into(),
+        }
+    }
+
+    fn mismatch(path: &str, expected: &str, value: &Value) -> Self {
+        let actual = match value {
+            Value::Null => "null",
+            Value::Bool(_) => "boolean",
+            Value::Number(n) if n.is_f64() => "number",
+            Value::Number(_) => "integer",
+            Value::String(_) => "string",
+            Value::Array(_) => "array",
+            Value::Object(_) => "object",
+        };
+        ValidationError::new(path, format!("Expected {}, got {}", expected, actual))
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.path, self.message)
+        }
+    }
+}
+
+fn split_name(name: &str) -> Option<(&str, &str)> {
+    let pos = name.find('.')?;
+    Some((&name[..pos], &name[pos + 1..]))
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Version {
+    pub major: String,
+    pub minor: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Domain {
+    pub domain: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub experimental: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub deprecated: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dependencies: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub types: Vec<TypeDef>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub commands: Vec<Command>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<Event>,
+}
+
+impl Domain {
+    pub fn command(&self, name: &str) -> Option<&Command> {
+        self.commands.iter().find(|command| command.name == name)
+    }
+
+    pub fn event(&self, name: &str) -> Option<&Event> {
+        self.events.iter().find(|event| event.name == name)
+    }
+
+    pub fn type_def(&self, id: &str) -> Option<&TypeDef> {
+        self.types.iter().find(|ty| ty.id == id)
+    }
+}
+
+/// A named type declared by a domain.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TypeDef {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub experimental: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub deprecated: bool,
+    #[serde(flatten)]
+    pub ty: Type,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Command {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub experimental: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub deprecated: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub parameters: Vec<Parameter>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub returns: Vec<Parameter>,
+    /// The domain that actually implements this command, if another one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redirect: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Event {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub experimental: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub deprecated: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub parameters: Vec<Parameter>,
+}
+
+/// A parameter or return value of a command or event, or a property of an
+/// object type.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Parameter {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub optional: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub experimental: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub deprecated: bool,
+    #[serde(flatten)]
+    pub ty: Type,
+}
+
+/// Either a reference to a named type or a type of its own.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Type {
+    #[serde(rename = "$ref", default, skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<TypeKind>,
+    /// Allowed values of a string.
+    #[serde(rename = "enum", default, skip_serializing_if = "Vec::is_empty")]
+    pub enum_values: Vec<String>,
+    /// The type of array items.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub items: Option<Box<Type>>,
+    /// Properties of an object. Empty if any properties are allowed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub properties: Vec<Parameter>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TypeKind {
+    Any,
+    Array,
+    Boolean,
+    Integer,
+    Number,
+    Object,
+    String,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+/// Stores schemas on disk by the browser's `Protocol-Version` and V8 version,
+/// so they can be used without a browser.
+#[derive(Debug, Clone)]
+pub struct SchemaCache {
+    dir: PathBuf,
+}
+
+impl SchemaCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        SchemaCache { dir: dir.into() }
+    }
+
+    /// `$XDG_CACHE_HOME/cdp-rs`, or `~/.cache/cdp-rs`.
+    pub fn default_dir() -> Option<PathBuf> {
+        let base = match std::env::var_os("XDG_CACHE_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+        };
+        Some(base.join("cdp-rs"))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn path(&self, protocol_version: &str, v8_version: &str) -> PathBuf {
+        // Keep the key usable as a file name whatever the browser reports.
+        let sanitize = |s: &str| -> String {
+            s.chars()
+                .map(|c| {
+                    if c.is_ascii_alphanumeric() || c == '.' {
+                        c
+                    } else {
+                        '_'
+                    }
+                })
+                .collect()
+        };
+        let name = format!(
+            "{}-{}.json",
+            sanitize(protocol_version),
+            sanitize(v8_version)
+        );
+        self.dir.join(name)
+    }
+
+    /// Returns `None` if there is no schema for these versions.
+    pub fn load(
+        &self,
+        protocol_version: &str,
+        v8_version: &str,
+    ) -> impl Future<Output = Result<Option<Protocol>, Error>> {
+        let path = self.path(protocol_version, v8_version);
+        async move {
+            let content = match blocking::unblock(move || fs::read(path)).await {
+                Ok(content) => content,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(err) => return Err(err.into()),
+            };
+            Ok(Some(serde_json::from_slice(&content)?))
+        }
+    }
+
+    pub fn store(
+        &self,
+        protocol_version: &str,
+        v8_version: &str,
+        protocol: &Protocol,
+    ) -> impl Future<Output = Result<(), Error>> {
+        let dir = self.dir.clone();
+        let path = self.path(protocol_version, v8_version);
+        let content = serde_json::to_vec(protocol);
+        async move {
+            let content = content?;
+            blocking::unblock(move || {
+                fs::create_dir_all(dir)?;
+                // Write to a temporary file first so that a reader never sees
+                // a partial schema.
+                let tmp = path.with_extension("json.tmp");
+                fs::write(&tmp, content)?;
+                fs::rename(tmp, path)
+            })
+            .await?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn bundled_schemas() {
+        for content in &[
+            include_str!("../protocol/browser_protocol.json"),
+            include_str!("../protocol/js_protocol.json"),
+        ] {
+            let protocol: Protocol = serde_json::from_str(content).unwrap();
+            let value: serde_json::Value = serde_json::from_str(content).unwrap();
+            // Nothing is lost when the schema is stored.
+            assert_eq!(serde_json::to_value(&protocol).unwrap(), value);
+        }
+
+        let content = include_str!("../protocol/browser_protocol.json");
+        let protocol: Protocol = serde_json::from_str(content).unwrap();
+        let navigate = protocol.command("Page.navigate").unwrap();
+        assert_eq!(navigate.parameters[0].name, "url");
+        assert_eq!(navigate.parameters[0].ty.kind, Some(TypeKind::String));
+        assert!(navigate.parameters[1].optional);
+        let frame_id = &navigate.returns[0].ty;
+        assert_eq!(frame_id.reference.as_deref(), Some("FrameId"));
+        assert!(protocol.type_def("Page", "FrameId").is_some());
+        assert!(protocol.type_def("Page", "Network.LoaderId").is_some());
+        assert!(protocol.event("Page.loadEventFired").is_some());
+        assert!(!protocol.domain("Page").unwrap().deprecated);
+    }
+
+    #[test]
+    fn validate() {
+        let content = include_str!("../protocol/browser_protocol.json");
+        let protocol: Protocol = serde_json::from_str(content).unwrap();
+        let errors = |method: &str, params: Value| -> Vec<String> {
+            match protocol.validate(method, &params) {
+                Ok(()) => Vec::new(),
+                Err(errors) => errors.iter().map(|err| err.to_string()).collect(),
+            }
+        };
+
+        let params = json!({"url": "about:blank", "transitionType": "link", "frameId": "F"});
+        assert!(errors("Page.navigate", params).is_empty());
+        assert!(errors("Page.enable", json!({})).is_empty());
+
+        assert_eq!(errors("Nope.enable", json!({})), ["Unknown domain Nope"]);
+        assert_eq!(errors("Page.nope", json!({})), ["Unknown method Page.nope"]);
+        assert_eq!(
+            errors("Page.enable", json!([])),
+            ["Expected object, got array"]
+        );
+        assert_eq!(
+            errors(
+                "Page.navigate",
+                json!({"transitionType": "bogus", "extra": 1})
+            ),
+            [
+                "url: Missing required parameter",
+                "transitionType: Expected one of \"link\", \"typed\", \"address_bar\", \
+                 \"auto_bookmark\", \"auto_subframe\", \"manual_subframe\", \"generated\", \
+                 \"auto_toplevel\", \"form_submit\", \"reload\", \"keyword\", \
+                 \"keyword_generated\", \"other\", got \"bogus\"",
+                "extra: Unknown parameter",
+            ]
+        );
+        // Array items and properties of referenced object types.
+        assert_eq!(
+            errors(
+                "Network.setCookies",
+                json!({"cookies": [{"name": "a", "value": "b"}, {"name": 1, "value": "c", "sameSite": "Lax"}]})
+            ),
+            ["cookies[1].name: Expected string, got integer"]
+        );
+        assert_eq!(
+            errors("DOM.describeNode", json!({"nodeId": 1.5, "depth": "1"})),
+            [
+                "nodeId: Expected integer, got number",
+                "depth: Expected integer, got string"
+            ]
+        );
+    }
+
+    #[test]
+    fn cache() {
+        let dir = std::env::temp_dir().join(format!("cdp-rs-schema-{}", std::process::id()));
+        let cache = SchemaCache::new(&dir);
+        let path = cache.path("1.3", "12.4.254.8/x");
+        assert_eq!(path, dir.join("1.3-12.4.254.8_x.json"));
+
+        let protocol = Protocol {
+            version: Version {
+                major: "1".to_owned(),
+                minor: "3".to_owned(),
+            },
+            domains: Vec::new(),
+        };
+        smol::run(async {
+            assert_eq!(cache.load("1.3", "1.0").await.unwrap(), None);
+            cache.store("1.3", "1.0", &protocol).await.unwrap();
+            assert_eq!(cache.load("1.3", "1.0").await.unwrap(), Some(protocol));
+            assert_eq!(cache.load("1.3", "2.0").await.unwrap(), None);
+        });
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
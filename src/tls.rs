@@ -0,0 +1,281 @@
+use std::convert::TryFrom;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use async_net::TcpStream;
+use futures::io::{AsyncRead, AsyncWrite};
+use futures_lite::io;
+use futures_rustls::client::TlsStream;
+use futures_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use futures_rustls::rustls::crypto::{self, CryptoProvider};
+use futures_rustls::rustls::{self, ClientConfig, DigitallySignedStruct, RootCertStore};
+use futures_rustls::TlsConnector;
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, ServerName, UnixTime};
+
+use crate::Error;
+
+/// TLS options for `https://` endpoints and `wss://` targets.
+#[derive(Debug, Clone, Default)]
+pub struct TlsConfig {
+    /// A PEM file with CA certificates to trust in addition to the built-in
+    /// roots.
+    pub ca_file: Option<PathBuf>,
+    /// Skips certificate verification. Only meant for lab setups with
+    /// self-signed certificates.
+    pub insecure: bool,
+}
+
+impl TlsConfig {
+    fn client_config(&self) -> Result<ClientConfig, Error> {
+        let provider = Arc::new(crypto::ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|err| Error::Tls(err.to_string()))?;
+
+        if self.insecure {
+            let verifier = Arc::new(NoVerifier(provider));
+            let config = builder
+                .dangerous()
+                .with_custom_certificate_verifier(verifier)
+                .with_no_client_auth();
+            return Ok(config);
+        }
+
+        let mut roots = RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        if let Some(ref ca_file) = self.ca_file {
+            let invalid_ca_file =
+                |err: &dyn std::fmt::Display| Error::Tls(format!("{}: {}", ca_file.display(), err));
+            let certs =
+                CertificateDer::pem_file_iter(ca_file).map_err(|err| invalid_ca_file(&err))?;
+            for cert in certs {
+                let cert = cert.map_err(|err| invalid_ca_file(&err))?;
+                roots.add(cert).map_err(|err| invalid_ca_file(&err))?;
+            }
+        }
+        Ok(builder.with_root_certificates(roots).with_no_client_auth())
+    }
+}
+
+/// Accepts any certificate. Signatures are still checked so that the
+/// handshake itself is sound.
+#[derive(Debug)]
+struct NoVerifier(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// A TCP stream, optionally wrapped in TLS.
+pub(crate) enum MaybeTlsStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+/// Connects to `host:port`, performing a TLS handshake if `tls` is given.
+pub(crate) async fn connect(
+    host: &str,
+    port: u16,
+    tls: Option<&TlsConfig>,
+) -> Result<MaybeTlsStream, Error> {
+    let stream = TcpStream::connect((host, port)).await?;
+    let tls = match tls {
+        Some(tls) => tls,
+        None => return Ok(MaybeTlsStream::Plain(stream)),
+    };
+
+    let connector = TlsConnector::from(Arc::new(tls.client_config()?));
+    let server_name =
+        ServerName::try_from(host.to_owned()).map_err(|err| Error::InvalidUrl(err.to_string()))?;
+    let stream = connector.connect(server_name, stream).await?;
+    Ok(MaybeTlsStream::Tls(Box::new(stream)))
+}
+
+impl AsyncRead for MaybeTlsStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            MaybeTlsStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for MaybeTlsStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            MaybeTlsStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            MaybeTlsStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_close(cx),
+            MaybeTlsStream::Tls(stream) => Pin::new(stream).poll_close(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_net::TcpListener;
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+    use futures_rustls::TlsAcceptor;
+    use rustls_pki_types::PrivateKeyDer;
+
+    /// A test CA, and a certificate it issued for 127.0.0.1.
+    const CA_FILE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/tls/ca.pem");
+    const SERVER_CERT: &[u8] = include_bytes!("../testdata/tls/server.pem");
+    const SERVER_KEY: &[u8] = include_bytes!("../testdata/tls/server.key");
+
+    /// Connects to a TLS server on 127.0.0.1 that writes "hello" once the
+    /// handshake is done, and reads it.
+    fn connect_to_server(config: &TlsConfig) -> Result<String, Error> {
+        let certs = CertificateDer::pem_slice_iter(SERVER_CERT)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let key = PrivateKeyDer::from_pem_slice(SERVER_KEY).unwrap();
+        let provider = Arc::new(crypto::ring::default_provider());
+        let server_config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+
+        smol::run(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let server = smol::Task::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                // The handshake fails if the client rejects the certificate.
+                if let Ok(mut stream) = acceptor.accept(stream).await {
+                    stream.write_all(b"hello").await.unwrap();
+                    stream.flush().await.unwrap();
+                }
+            });
+            let mut stream = connect("127.0.0.1", port, Some(config)).await?;
+            let mut buf = [0; 5];
+            stream.read_exact(&mut buf).await?;
+            server.await;
+            Ok(String::from_utf8_lossy(&buf).into_owned())
+        })
+    }
+
+    #[test]
+    fn ca_file() {
+        let config = TlsConfig {
+            ca_file: Some(CA_FILE.into()),
+            ..TlsConfig::default()
+        };
+        assert_eq!(connect_to_server(&config).unwrap(), "hello");
+
+        // Not trusted by the built-in roots.
+        assert!(connect_to_server(&TlsConfig::default()).is_err());
+    }
+
+    #[test]
+    fn invalid_ca_file() {
+        let config = TlsConfig {
+            ca_file: Some("/nonexistent/ca.pem".into()),
+            ..TlsConfig::default()
+        };
+        match config.client_config() {
+            Err(Error::Tls(message)) => assert!(message.starts_with("/nonexistent/ca.pem: ")),
+            res => panic!("unexpected {:?}", res.map(|_| ())),
+        }
+
+        let path = std::env::temp_dir().join(format!("cdp-rs-bad-ca-{}.pem", std::process::id()));
+        std::fs::write(
+            &path,
+            "-----BEGIN CERTIFICATE-----\nnot base64!\n-----END CERTIFICATE-----\n",
+        )
+        .unwrap();
+        let config = TlsConfig {
+            ca_file: Some(path.clone()),
+            ..TlsConfig::default()
+        };
+        assert!(matches!(config.client_config(), Err(Error::Tls(_))));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn insecure() {
+        let config = TlsConfig {
+            insecure: true,
+            ..TlsConfig::default()
+        };
+        assert_eq!(connect_to_server(&config).unwrap(), "hello");
+
+        // A self-signed certificate for another name is accepted too.
+        let verifier = NoVerifier(Arc::new(crypto::ring::default_provider()));
+        let cert = CertificateDer::from_pem_file(CA_FILE).unwrap();
+        let server_name = ServerName::try_from("example.com").unwrap();
+        assert!(verifier
+            .verify_server_cert(&cert, &[], &server_name, &[], UnixTime::now())
+            .is_ok());
+    }
+}
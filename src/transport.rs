@@ -0,0 +1,447 @@
+//! Transports carry DevTools messages between a `WebSocketTarget` and a
+//! browser.
+//!
+//! Besides the WebSocket client and pipes, there is an in-memory [`channel`]
+//! that lets a test play the browser, and a [`Recorder`] and [`Replayer`] to
+//! capture a session with a real browser and play it back without one.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use futures::channel::mpsc;
+use futures::{Sink, Stream};
+use serde::{Deserialize, Serialize};
+
+use crate::websocket::Message;
+use crate::Error;
+
+/// A bidirectional message channel to DevTools.
+///
+/// The sender sends messages and, when closed, ends the connection. The
+/// receiver yields incoming messages; a `Message::Close` means the browser
+/// ended the connection, and the stream ending without one means it was
+/// lost. Any pair of such a sink and stream is a transport, e.g. the halves
+/// returned by `websocket::connect` or `pipe::new`.
+pub trait Transport {
+    type Sender: Sink<Message, Error = Error> + Send + Unpin + 'static;
+    type Receiver: Stream<Item = Result<Message, Error>> + Send + Unpin + 'static;
+
+    fn split(self) -> (Self::Sender, Self::Receiver);
+}
+
+impl<S, R> Transport for (S, R)
+where
+    S: Sink<Message, Error = Error> + Send + Unpin + 'static,
+    R: Stream<Item = Result<Message, Error>> + Send + Unpin + 'static,
+{
+    type Sender = S;
+    type Receiver = R;
+
+    fn split(self) -> (S, R) {
+        self
+    }
+}
+
+/// Returns two connected in-memory transports. Messages sent on one are
+/// received on the other. Closing a sender makes the other side receive
+/// `Message::Close(None)`.
+pub fn channel() -> (Channel, Channel) {
+    let (a_tx, a_rx) = mpsc::unbounded();
+    let (b_tx, b_rx) = mpsc::unbounded();
+    let a = Channel {
+        sender: ChannelSender { tx: a_tx },
+        receiver: ChannelReceiver { rx: b_rx },
+    };
+    let b = Channel {
+        sender: ChannelSender { tx: b_tx },
+        receiver: ChannelReceiver { rx: a_rx },
+    };
+    (a, b)
+}
+
+/// One end of an in-memory transport created by [`channel`].
+pub struct Channel {
+    sender: ChannelSender,
+    receiver: ChannelReceiver,
+}
+
+impl Transport for Channel {
+    type Sender = ChannelSender;
+    type Receiver = ChannelReceiver;
+
+    fn split(self) -> (ChannelSender, ChannelReceiver) {
+        (self.sender, self.receiver)
+    }
+}
+
+pub struct ChannelSender {
+    tx: mpsc::UnboundedSender<Message>,
+}
+
+impl Sink<Message> for ChannelSender {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Error> {
+        self.tx
+            .unbounded_send(item)
+            .map_err(|_| Error::Disconnected("Channel closed".to_owned()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        if !self.tx.is_closed() {
+            let _ = self.tx.unbounded_send(Message::Close(None));
+            self.tx.close_channel();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+pub struct ChannelReceiver {
+    rx: mpsc::UnboundedReceiver<Message>,
+}
+
+impl Stream for ChannelReceiver {
+    type Item = Result<Message, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.rx)
+            .poll_next(cx)
+            .map(|item| item.map(Ok))
+    }
+}
+
+/// A message of a recorded session. Messages are kept as JSON values, so a
+/// recording is easy to read and edit.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Record {
+    /// Sent to the browser.
+    Sent(serde_json::Value),
+    /// Received from the browser.
+    Received(serde_json::Value),
+}
+
+/// The messages a [`Recorder`] has seen, shared with it.
+#[derive(Debug, Clone, Default)]
+pub struct Recording {
+    records: Arc<Mutex<Vec<Record>>>,
+}
+
+impl Recording {
+    pub fn records(&self) -> Vec<Record> {
+        self.records.lock().unwrap().clone()
+    }
+
+    fn push(&self, record: Record) {
+        self.records.lock().unwrap().push(record);
+    }
+
+    /// Parses records written by `Display`, one JSON object per line.
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let records = s
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(serde_json::from_str)
+            .collect::<Result<Vec<Record>, _>>()?;
+        Ok(Recording {
+            records: Arc::new(Mutex::new(records)),
+        })
+    }
+}
+
+impl fmt::Display for Recording {
+    /// Writes one record per line, e.g. `{"sent":{"id":0,...}}`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for record in self.records.lock().unwrap().iter() {
+            writeln!(
+                f,
+                "{}",
+                serde_json::to_string(record).map_err(|_| fmt::Error)?
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses a text or binary message as JSON for a record.
+fn message_json(message: &Message) -> Option<serde_json::Value> {
+    match message {
+        Message::Text(text) => serde_json::from_str(text).ok(),
+        Message::Binary(data) => serde_json::from_slice(data).ok(),
+        Message::Ping(_) | Message::Pong(_) | Message::Close(_) => None,
+    }
+}
+
+/// Wraps a transport and records the messages that pass through it.
+pub struct Recorder<T> {
+    transport: T,
+    recording: Recording,
+}
+
+impl<T: Transport> Recorder<T> {
+    /// Returns the recorder and a handle to what it records.
+    pub fn new(transport: T) -> (Self, Recording) {
+        let recording = Recording::default();
+        let recorder = Recorder {
+            transport,
+            recording: recording.clone(),
+        };
+        (recorder, recording)
+    }
+}
+
+impl<T: Transport> Transport for Recorder<T> {
+    type Sender = RecordingSender<T::Sender>;
+    type Receiver = RecordingReceiver<T::Receiver>;
+
+    fn split(self) -> (Self::Sender, Self::Receiver) {
+        let (sender, receiver) = self.transport.split();
+        let sender = RecordingSender {
+            inner: sender,
+            recording: self.recording.clone(),
+        };
+        let receiver = RecordingReceiver {
+            inner: receiver,
+            recording: self.recording,
+        };
+        (sender, receiver)
+    }
+}
+
+pub struct RecordingSender<S> {
+    inner: S,
+    recording: Recording,
+}
+
+impl<S: Sink<Message, Error = Error> + Unpin> Sink<Message> for RecordingSender<S> {
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.inner).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Error> {
+        if let Some(value) = message_json(&item) {
+            self.recording.push(Record::Sent(value));
+        }
+        Pin::new(&mut self.inner).start_send(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+pub struct RecordingReceiver<R> {
+    inner: R,
+    recording: Recording,
+}
+
+impl<R: Stream<Item = Result<Message, Error>> + Unpin> Stream for RecordingReceiver<R> {
+    type Item = Result<Message, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let item = Pin::new(&mut self.inner).poll_next(cx);
+        if let Poll::Ready(Some(Ok(ref message))) = item {
+            if let Some(value) = message_json(message) {
+                self.recording.push(Record::Received(value));
+            }
+        }
+        item
+    }
+}
+
+/// Plays back a recording in place of a browser. Each sent message must
+/// equal the next one recorded; the messages received after it in the
+/// recording are then delivered. Messages recorded as received before the
+/// first send are delivered right away.
+pub struct Replayer {
+    sender: ReplaySender,
+    receiver: ChannelReceiver,
+}
+
+impl Replayer {
+    pub fn new(recording: &Recording) -> Self {
+        let (tx, rx) = mpsc::unbounded();
+        let mut sender = ReplaySender {
+            records: recording.records().into(),
+            tx,
+        };
+        sender.deliver_received();
+        Replayer {
+            sender,
+            receiver: ChannelReceiver { rx },
+        }
+    }
+}
+
+impl Transport for Replayer {
+    type Sender = ReplaySender;
+    type Receiver = ChannelReceiver;
+
+    fn split(self) -> (ReplaySender, ChannelReceiver) {
+        (self.sender, self.receiver)
+    }
+}
+
+pub struct ReplaySender {
+    records: VecDeque<Record>,
+    tx: mpsc::UnboundedSender<Message>,
+}
+
+impl ReplaySender {
+    /// Delivers the received messages up to the next sent one.
+    fn deliver_received(&mut self) {
+        while let Some(Record::Received(value)) = self.records.front() {
+            let _ = self.tx.unbounded_send(Message::Text(value.to_string()));
+            self.records.pop_front();
+        }
+    }
+}
+
+impl Sink<Message> for ReplaySender {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Error> {
+        let value = match message_json(&item) {
+            Some(value) => value,
+            None => return Ok(()),
+        };
+        match self.records.pop_front() {
+            Some(Record::Sent(ref expected)) if *expected == value => {
+                self.deliver_received();
+                Ok(())
+            }
+            Some(Record::Sent(expected)) => Err(Error::Replay(format!(
+                "Sent {}, but the recording has {}",
+                value, expected
+            ))),
+            _ => Err(Error::Replay(format!(
+                "Sent {} after the end of the recording",
+                value
+            ))),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        if !self.tx.is_closed() {
+            let _ = self.tx.unbounded_send(Message::Close(None));
+            self.tx.close_channel();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EventFilter, MethodCall, WebSocketTarget};
+    use futures::{future, SinkExt, StreamExt};
+    use serde_json::json;
+
+    /// Plays a browser that replies to every call with its params and sends
+    /// an event first.
+    async fn fake_browser(browser: Channel) {
+        let (mut sender, mut receiver) = browser.split();
+        while let Some(Ok(message)) = receiver.next().await {
+            let call: serde_json::Value = match message {
+                Message::Text(text) => serde_json::from_str(&text).unwrap(),
+                _ => break,
+            };
+            let event = json!({"method": "Fake.called", "params": {"method": call["method"]}});
+            let reply = json!({"id": call["id"], "result": call["params"]});
+            sender.send(Message::Text(event.to_string())).await.unwrap();
+            sender.send(Message::Text(reply.to_string())).await.unwrap();
+        }
+        sender.close().await.unwrap();
+    }
+
+    #[test]
+    fn channel_transport() {
+        let (client, browser) = channel();
+        let (target, driver) = WebSocketTarget::new(client);
+        let mut events = target.subscribe(EventFilter::Domain("Fake".to_owned()));
+        let test = async {
+            let method = MethodCall::new("Page.navigate", json!({"url": "about:blank"}));
+            let result = target.call_method(&method).await.unwrap();
+            assert_eq!(result, json!({"url": "about:blank"}));
+            let event = events.next().await.unwrap();
+            assert_eq!(event.method, "Fake.called");
+            assert_eq!(event.params, json!({"method": "Page.navigate"}));
+
+            target.close().await.unwrap();
+            assert!(events.next().await.is_none());
+        };
+        // No runtime is needed when the driver is polled with the calls.
+        let (_, driver_result, _) =
+            futures::executor::block_on(future::join3(fake_browser(browser), driver, test));
+        driver_result.unwrap();
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let (client, browser) = channel();
+        let (recorder, recording) = Recorder::new(client);
+        smol::run(async {
+            smol::Task::spawn(fake_browser(browser)).detach();
+            let (target, driver) = WebSocketTarget::new(recorder);
+            smol::Task::spawn(driver).detach();
+            let method = MethodCall::new("DOM.getDocument", json!({"depth": 1}));
+            target.call_method(&method).await.unwrap();
+            target.close().await.unwrap();
+        });
+        let text = recording.to_string();
+        assert_eq!(
+            text.lines().collect::<Vec<_>>(),
+            vec![
+                r#"{"sent":{"id":0,"method":"DOM.getDocument","params":{"depth":1}}}"#,
+                r#"{"received":{"method":"Fake.called","params":{"method":"DOM.getDocument"}}}"#,
+                r#"{"received":{"id":0,"result":{"depth":1}}}"#,
+            ]
+        );
+
+        let recording = Recording::parse(&text).unwrap();
+        smol::run(async {
+            let (target, driver) = WebSocketTarget::new(Replayer::new(&recording));
+            smol::Task::spawn(driver).detach();
+            let mut events = target.subscribe(EventFilter::All);
+            let method = MethodCall::new("DOM.getDocument", json!({"depth": 1}));
+            assert_eq!(
+                target.call_method(&method).await.unwrap(),
+                json!({"depth": 1})
+            );
+            assert_eq!(events.next().await.unwrap().method, "Fake.called");
+
+            // The recording has no more calls.
+            let method = MethodCall::new("DOM.enable", json!({}));
+            match target.call_method(&method).await {
+                Err(Error::Replay(_)) => (),
+                res => panic!("unexpected {:?}", res),
+            }
+            target.close().await.unwrap();
+        });
+    }
+}
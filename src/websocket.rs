@@ -1,10 +1,20 @@
-use async_net::TcpStream;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use async_io::Timer;
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadHalf, WriteHalf};
+use futures::task::ArcWake;
+use futures::{ready, Sink, Stream};
+use futures_lite::io;
+use futures_lite::*;
 use rand::Rng;
-use smol::io;
-use smol::prelude::*;
 use url::Url;
 
+use crate::deflate::{Compressor, Decompressor, DeflateConfig, DeflateParams};
 use crate::endpoints::read_raw_header;
+use crate::tls::{self, MaybeTlsStream, TlsConfig};
 use crate::Error;
 
 #[derive(Debug, Copy, Clone)]
@@ -18,6 +28,10 @@ pub(crate) enum Opcode {
 }
 
 impl Opcode {
+    fn is_control(self) -> bool {
+        (self as u8) & 0x8 != 0
+    }
+
     fn from_u8(value: u8) -> Result<Opcode, Error> {
         match value {
             0x0 => Ok(Opcode::ContinuationFrame),
@@ -26,7 +40,7 @@ impl Opcode {
             0x8 => Ok(Opcode::Close),
             0x9 => Ok(Opcode::Ping),
             0xa => Ok(Opcode::Pong),
-            _ => Err(format!("Invalid opcode: {}", value).into()),
+            _ => Err(Error::Protocol(format!("Invalid opcode: {}", value))),
         }
     }
 }
@@ -34,6 +48,8 @@ impl Opcode {
 #[derive(Debug)]
 pub(crate) struct FrameHeader {
     pub(crate) fin: bool,
+    /// Set on the first frame of a compressed message.
+    pub(crate) rsv1: bool,
     pub(crate) opcode: Opcode,
     pub(crate) mask: bool,
     pub(crate) payload_len: usize,
@@ -46,149 +62,769 @@ pub(crate) struct Frame {
     pub(crate) payload: Vec<u8>,
 }
 
-pub(crate) struct Sender {
-    stream: TcpStream,
+/// Status code for a normal closure.
+pub(crate) const CLOSE_NORMAL: u16 = 1000;
+/// Status code sent when the peer violated the protocol.
+pub(crate) const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+/// Status code sent when a text message isn't valid UTF-8.
+pub(crate) const CLOSE_INVALID_PAYLOAD: u16 = 1007;
+/// Status code sent when a message is too big to process.
+pub(crate) const CLOSE_MESSAGE_TOO_BIG: u16 = 1009;
+
+/// The payload of a close frame.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CloseFrame {
+    pub code: u16,
+    pub reason: String,
+}
+
+impl std::fmt::Display for CloseFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.reason.is_empty() {
+            write!(f, "{}", self.code)
+        } else {
+            write!(f, "{} {}", self.code, self.reason)
+        }
+    }
+}
+
+/// A WebSocket message. `Receiver` only yields `Text`, `Binary` and
+/// `Close`; pings are answered automatically.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close(Option<CloseFrame>),
+}
+
+/// Default limit of an incoming message: 256 MiB.
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 256 << 20;
+
+/// Connection options.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Maximum size of an incoming message in bytes, after reassembly.
+    /// `None` means unlimited.
+    pub max_message_size: Option<usize>,
+    /// Outgoing messages larger than this are sent as fragments of at most
+    /// this many bytes. `None` sends every message as a single frame.
+    pub max_frame_size: Option<usize>,
+    /// Sends a ping at this interval while receiving. The connection fails
+    /// if the pong doesn't arrive before the next ping is due.
+    pub keepalive_interval: Option<Duration>,
+    /// Used for `wss://` URLs.
+    pub tls: TlsConfig,
+    /// Offers permessage-deflate if set.
+    pub deflate: Option<DeflateConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            max_message_size: Some(DEFAULT_MAX_MESSAGE_SIZE),
+            max_frame_size: None,
+            keepalive_interval: None,
+            tls: TlsConfig::default(),
+            deflate: Some(DeflateConfig::default()),
+        }
+    }
+}
+
+/// Wakes every task waiting on the write side. Both `Sender` and `Receiver`
+/// write to the same stream, which may only remember the last waker.
+#[derive(Default)]
+struct WakerSet(Mutex<Vec<Waker>>);
+
+impl WakerSet {
+    fn register(&self, waker: &Waker) {
+        let mut wakers = self.0.lock().unwrap();
+        if wakers.iter().all(|w| !w.will_wake(waker)) {
+            wakers.push(waker.clone());
+        }
+    }
+}
+
+impl ArcWake for WakerSet {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        let wakers = std::mem::take(&mut *arc_self.0.lock().unwrap());
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+/// Encoded frames waiting to be written. Shared by `Sender` and `Receiver`
+/// so that the receiving side can reply to pings and close frames.
+struct Writer {
+    stream: WriteHalf<MaybeTlsStream>,
+    buf: Vec<u8>,
+    close_sent: bool,
+    wakers: Arc<WakerSet>,
+    compressor: Option<Compressor>,
+}
+
+impl Writer {
+    fn queue(&mut self, message: Message, max_frame_size: Option<usize>) -> Result<(), Error> {
+        if self.close_sent {
+            return Err(Error::Disconnected("Close frame already sent".to_owned()));
+        }
+        let (opcode, payload) = match message {
+            Message::Text(text) => (Opcode::TextFrame, text.into_bytes()),
+            Message::Binary(data) => (Opcode::BinaryFrame, data),
+            Message::Ping(data) => (Opcode::Ping, data),
+            Message::Pong(data) => (Opcode::Pong, data),
+            Message::Close(frame) => {
+                self.close_sent = true;
+                (Opcode::Close, encode_close_payload(frame.as_ref()))
+            }
+        };
+        match opcode {
+            Opcode::TextFrame | Opcode::BinaryFrame => {
+                let (compressed, payload) = match self.compressor {
+                    Some(ref mut compressor) if compressor.should_compress(&payload) => {
+                        (true, compressor.compress(&payload)?)
+                    }
+                    _ => (false, payload),
+                };
+                encode_message(&mut self.buf, opcode, compressed, &payload, max_frame_size)
+            }
+            _ => encode_frame(&mut self.buf, opcode, true, false, &payload),
+        }
+        Ok(())
+    }
+
+    fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.wakers.register(cx.waker());
+        let waker = futures::task::waker(self.wakers.clone());
+        let mut cx = Context::from_waker(&waker);
+        while !self.buf.is_empty() {
+            let n = ready!(Pin::new(&mut self.stream).poll_write(&mut cx, &self.buf))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::Error::from(io::ErrorKind::WriteZero)));
+            }
+            self.buf.drain(..n);
+        }
+        Pin::new(&mut self.stream).poll_flush(&mut cx)
+    }
+}
+
+/// The sending half of a WebSocket connection. Closing the sink starts the
+/// close handshake if no close frame has been sent yet.
+pub struct Sender {
+    writer: Arc<Mutex<Writer>>,
+    max_frame_size: Option<usize>,
 }
 
 impl Sender {
-    fn new(stream: TcpStream) -> Self {
-        Sender { stream }
+    fn new(writer: Arc<Mutex<Writer>>, config: &Config) -> Self {
+        Sender {
+            writer,
+            max_frame_size: config.max_frame_size,
+        }
+    }
+}
+
+impl Sink<Message> for Sender {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        // Flush the previous message before accepting a new one.
+        self.poll_flush(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Error> {
+        self.writer.lock().unwrap().queue(item, self.max_frame_size)
     }
 
-    pub(crate) fn send_text_frame(&self, text: String) -> impl Future<Output = Result<(), Error>> {
-        send_text_frame(self.stream.clone(), text)
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let mut writer = self.writer.lock().unwrap();
+        ready!(writer.poll_flush(cx))?;
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let mut writer = self.writer.lock().unwrap();
+        if !writer.close_sent {
+            let frame = CloseFrame {
+                code: CLOSE_NORMAL,
+                reason: String::new(),
+            };
+            writer.queue(Message::Close(Some(frame)), None)?;
+        }
+        // The browser closes the TCP connection once it replies.
+        ready!(writer.poll_flush(cx))?;
+        Poll::Ready(Ok(()))
     }
 }
 
-pub(crate) struct Receiver {
-    reader: io::BufReader<TcpStream>,
+struct Keepalive {
+    interval: Duration,
+    timer: Timer,
+    awaiting_pong: bool,
+}
+
+const READ_CHUNK_SIZE: usize = 8192;
+
+/// Control frames must have a payload of 125 bytes or less.
+const MAX_CONTROL_PAYLOAD_LEN: usize = 125;
+
+/// A fragmented message being reassembled.
+struct Fragments {
+    opcode: Opcode,
+    compressed: bool,
+    data: Vec<u8>,
+    // Length of `data` known to be valid UTF-8, for text messages.
+    utf8_valid_up_to: usize,
+}
+
+impl Fragments {
+    fn new(opcode: Opcode, compressed: bool) -> Self {
+        Fragments {
+            opcode,
+            compressed,
+            data: Vec::new(),
+            utf8_valid_up_to: 0,
+        }
+    }
+
+    /// Appends a fragment. Uncompressed text is validated as it arrives so
+    /// that invalid messages fail fast; a code point may span fragments.
+    fn push(&mut self, payload: &[u8]) -> Result<(), Error> {
+        self.data.extend_from_slice(payload);
+        if let (Opcode::TextFrame, false) = (self.opcode, self.compressed) {
+            match std::str::from_utf8(&self.data[self.utf8_valid_up_to..]) {
+                Ok(_) => self.utf8_valid_up_to = self.data.len(),
+                Err(err) if err.error_len().is_none() => self.utf8_valid_up_to += err.valid_up_to(),
+                Err(_) => return Err(invalid_utf8()),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The receiving half of a WebSocket connection. Yields complete messages,
+/// reassembling fragmented ones. The stream ends after yielding
+/// `Message::Close`.
+pub struct Receiver {
+    stream: ReadHalf<MaybeTlsStream>,
+    writer: Arc<Mutex<Writer>>,
+    buf: Vec<u8>,
+    fragments: Option<Fragments>,
+    max_message_size: Option<usize>,
+    keepalive: Option<Keepalive>,
+    decompressor: Option<Decompressor>,
+    closed: bool,
 }
 
 impl Receiver {
-    fn new(stream: TcpStream) -> Self {
-        let reader = io::BufReader::new(stream);
-        Receiver { reader }
+    fn new(stream: ReadHalf<MaybeTlsStream>, writer: Arc<Mutex<Writer>>, config: &Config) -> Self {
+        let keepalive = config.keepalive_interval.map(|interval| Keepalive {
+            interval,
+            timer: Timer::new(interval),
+            awaiting_pong: false,
+        });
+        Receiver {
+            stream,
+            writer,
+            buf: Vec::new(),
+            fragments: None,
+            max_message_size: config.max_message_size,
+            keepalive,
+            decompressor: None,
+            closed: false,
+        }
     }
 
-    pub(crate) async fn receive_frame(&mut self) -> Result<Frame, Error> {
-        receive_frame(&mut self.reader).await
+    fn check_message_size(&self, header: &FrameHeader) -> Result<(), Error> {
+        let max_message_size = match self.max_message_size {
+            Some(size) => size,
+            None => return Ok(()),
+        };
+        let received = match self.fragments {
+            Some(ref fragments) => fragments.data.len(),
+            None => 0,
+        };
+        if received.saturating_add(header.payload_len) > max_message_size {
+            return Err(Error::Protocol(format!(
+                "Message exceeds {} bytes",
+                max_message_size
+            )));
+        }
+        Ok(())
+    }
+
+    /// Queues a control frame. Errors are ignored after a close frame has
+    /// been sent since the peer doesn't expect anything else.
+    fn reply(&self, message: Message) {
+        let mut writer = self.writer.lock().unwrap();
+        if !writer.close_sent {
+            let _ = writer.queue(message, None);
+        }
+    }
+
+    fn poll_keepalive(&mut self, cx: &mut Context<'_>) -> Result<(), Error> {
+        let keepalive = match self.keepalive {
+            Some(ref mut keepalive) => keepalive,
+            None => return Ok(()),
+        };
+        if Pin::new(&mut keepalive.timer).poll(cx).is_pending() {
+            return Ok(());
+        }
+        if keepalive.awaiting_pong {
+            return Err(Error::Disconnected("Keepalive ping timed out".to_owned()));
+        }
+        keepalive.awaiting_pong = true;
+        keepalive.timer.reset(keepalive.interval);
+        // Register the timer again.
+        let _ = Pin::new(&mut keepalive.timer).poll(cx);
+        self.reply(Message::Ping(Vec::new()));
+        Ok(())
+    }
+
+    /// Processes buffered frames until a message completes. Returns `None` if
+    /// more data is needed. Errors come with the status code to close with.
+    fn next_message(&mut self) -> Result<Option<Message>, (u16, Error)> {
+        let protocol_error = |err| (CLOSE_PROTOCOL_ERROR, err);
+        loop {
+            // Reject oversized messages before buffering their payloads.
+            if let Some((header, _)) = read_header(&self.buf).map_err(protocol_error)? {
+                if let Opcode::TextFrame | Opcode::BinaryFrame | Opcode::ContinuationFrame =
+                    header.opcode
+                {
+                    self.check_message_size(&header)
+                        .map_err(|err| (CLOSE_MESSAGE_TOO_BIG, err))?;
+                }
+            }
+
+            let frame = match decode_frame(&mut self.buf).map_err(protocol_error)? {
+                Some(frame) => frame,
+                None => return Ok(None),
+            };
+            if let Some(message) = self.handle_frame(frame)? {
+                return Ok(Some(message));
+            }
+        }
+    }
+
+    /// Starts the close handshake after a failure.
+    fn fail(&mut self, code: u16, err: &Error) {
+        // Control frame payloads are limited to 125 bytes.
+        let mut reason = err.to_string();
+        while reason.len() > 123 {
+            reason.pop();
+        }
+        self.reply(Message::Close(Some(CloseFrame { code, reason })));
+        self.closed = true;
+    }
+
+    /// Builds a message from a complete payload, decompressing it if needed.
+    fn finish_message(
+        &mut self,
+        opcode: Opcode,
+        compressed: bool,
+        payload: Vec<u8>,
+    ) -> Result<Message, (u16, Error)> {
+        let invalid_payload = |err| (CLOSE_INVALID_PAYLOAD, err);
+        let payload = match self.decompressor {
+            Some(ref mut decompressor) if compressed => {
+                let payload = decompressor
+                    .decompress(&payload, self.max_message_size)
+                    .map_err(invalid_payload)?;
+                if let Some(max_message_size) = self.max_message_size {
+                    if payload.len() > max_message_size {
+                        let err = format!("Message exceeds {} bytes", max_message_size);
+                        let err = Error::Protocol(err);
+                        return Err((CLOSE_MESSAGE_TOO_BIG, err));
+                    }
+                }
+                payload
+            }
+            _ => payload,
+        };
+        to_message(opcode, payload).map_err(invalid_payload)
+    }
+
+    fn handle_frame(&mut self, frame: Frame) -> Result<Option<Message>, (u16, Error)> {
+        let Frame { header, payload } = frame;
+        let invalid_payload = |err| (CLOSE_INVALID_PAYLOAD, err);
+        if header.rsv1 {
+            // Only the first frame of a data message may be compressed.
+            let allowed = match header.opcode {
+                Opcode::TextFrame | Opcode::BinaryFrame => self.decompressor.is_some(),
+                _ => false,
+            };
+            if !allowed {
+                return Err((
+                    CLOSE_PROTOCOL_ERROR,
+                    Error::Protocol("Unexpected RSV1 bit".to_owned()),
+                ));
+            }
+        }
+        match header.opcode {
+            Opcode::TextFrame | Opcode::BinaryFrame => {
+                if self.fragments.is_some() {
+                    let err = Error::Protocol("Expected a continuation frame".to_owned());
+                    return Err((CLOSE_PROTOCOL_ERROR, err));
+                }
+                if header.fin {
+                    return self
+                        .finish_message(header.opcode, header.rsv1, payload)
+                        .map(Some);
+                }
+                let mut fragments = Fragments::new(header.opcode, header.rsv1);
+                fragments.push(&payload).map_err(invalid_payload)?;
+                self.fragments = Some(fragments);
+                Ok(None)
+            }
+            Opcode::ContinuationFrame => {
+                let fragments = match self.fragments {
+                    Some(ref mut fragments) => fragments,
+                    None => {
+                        let err = Error::Protocol("Unexpected continuation frame".to_owned());
+                        return Err((CLOSE_PROTOCOL_ERROR, err));
+                    }
+                };
+                fragments.push(&payload).map_err(invalid_payload)?;
+                if !header.fin {
+                    return Ok(None);
+                }
+                let fragments = self.fragments.take().unwrap();
+                self.finish_message(fragments.opcode, fragments.compressed, fragments.data)
+                    .map(Some)
+            }
+            Opcode::Ping => {
+                self.reply(Message::Pong(payload));
+                Ok(None)
+            }
+            Opcode::Pong => {
+                if let Some(ref mut keepalive) = self.keepalive {
+                    keepalive.awaiting_pong = false;
+                }
+                Ok(None)
+            }
+            Opcode::Close => {
+                let frame = decode_close_payload(&payload)?;
+                // Echo the status code to complete the handshake.
+                self.reply(Message::Close(frame.clone()));
+                self.closed = true;
+                Ok(Some(Message::Close(frame)))
+            }
+        }
     }
 }
 
-pub(crate) async fn connect(url: Url) -> Result<(Sender, Receiver), Error> {
-    let stream = connect_stream(url).await?;
-    let sender = Sender::new(stream.clone());
-    let receiver = Receiver::new(stream);
+impl Stream for Receiver {
+    type Item = Result<Message, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        if this.closed {
+            // Make sure the reply to the close frame goes out.
+            ready!(this.writer.lock().unwrap().poll_flush(cx))?;
+            return Poll::Ready(None);
+        }
+
+        this.poll_keepalive(cx)?;
+        loop {
+            let next = this.next_message();
+
+            // Write control frames queued so far. A stalled write shouldn't
+            // stop reading.
+            if let Poll::Ready(Err(err)) = this.writer.lock().unwrap().poll_flush(cx) {
+                return Poll::Ready(Some(Err(err.into())));
+            }
+
+            match next {
+                Ok(Some(message)) => return Poll::Ready(Some(Ok(message))),
+                Ok(None) => (),
+                Err((code, err)) => {
+                    this.fail(code, &err);
+                    return Poll::Ready(Some(Err(err)));
+                }
+            }
+
+            let mut chunk = [0; READ_CHUNK_SIZE];
+            let n = ready!(Pin::new(&mut this.stream).poll_read(cx, &mut chunk))?;
+            if n == 0 {
+                if this.buf.is_empty() && this.fragments.is_none() {
+                    return Poll::Ready(None);
+                }
+                let err = io::Error::from(io::ErrorKind::UnexpectedEof);
+                return Poll::Ready(Some(Err(err.into())));
+            }
+            this.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+fn invalid_utf8() -> Error {
+    Error::Protocol("Invalid UTF-8 in text".to_owned())
+}
+
+fn to_message(opcode: Opcode, payload: Vec<u8>) -> Result<Message, Error> {
+    match opcode {
+        Opcode::TextFrame => {
+            let text = String::from_utf8(payload).map_err(|_| invalid_utf8())?;
+            Ok(Message::Text(text))
+        }
+        _ => Ok(Message::Binary(payload)),
+    }
+}
+
+pub async fn connect(url: Url, config: &Config) -> Result<(Sender, Receiver), Error> {
+    let (stream, leftover, deflate) = connect_stream(url, config).await?;
+    let (read_half, write_half) = stream.split();
+    let compressor = match (deflate, config.deflate.as_ref()) {
+        (Some(params), Some(deflate_config)) => Some(Compressor::new(params, deflate_config)),
+        _ => None,
+    };
+    let writer = Arc::new(Mutex::new(Writer {
+        stream: write_half,
+        buf: Vec::new(),
+        close_sent: false,
+        wakers: Arc::new(WakerSet::default()),
+        compressor,
+    }));
+    let sender = Sender::new(writer.clone(), config);
+    let mut receiver = Receiver::new(read_half, writer, config);
+    receiver.decompressor = deflate.map(Decompressor::new);
+    // Frames may have arrived right after the handshake response.
+    receiver.buf = leftover;
     Ok((sender, receiver))
 }
 
-async fn connect_stream(url: Url) -> Result<TcpStream, Error> {
+/// Connects to `url` and performs the opening handshake. Returns the stream,
+/// any bytes read past the handshake response, and the permessage-deflate
+/// parameters if the browser accepted the extension.
+async fn connect_stream(
+    url: Url,
+    config: &Config,
+) -> Result<(MaybeTlsStream, Vec<u8>, Option<DeflateParams>), Error> {
+    let (tls, origin_scheme, default_port) = match url.scheme() {
+        "ws" => (None, "http", 9222),
+        "wss" => (Some(&config.tls), "https", 443),
+        scheme => {
+            let reason = format!("Unsupported scheme: {}", scheme);
+            return Err(Error::InvalidUrl(reason));
+        }
+    };
     let host = match url.host_str() {
         Some(host) => host,
-        None => return Err("No host".into()),
+        None => return Err(Error::InvalidUrl("No host".to_owned())),
     };
-    let port = match url.port() {
-        Some(port) => port,
-        None => 9222,
+    let port = url.port().unwrap_or(default_port);
+    let path = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_owned(),
     };
-    let path = url.path();
-    let origin = format!("http://{}", host);
+    let host_header = format!("{}:{}", host, port);
+    let origin = format!("{}://{}", origin_scheme, host);
     let random_value = rand::thread_rng().gen::<[u8; 16]>();
     let key = base64::encode(random_value);
 
+    let extensions = match config.deflate {
+        Some(ref deflate) => format!("Sec-WebSocket-Extensions: {}\r\n", deflate.offer()),
+        None => String::new(),
+    };
+
     let request = format!(
-        "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nOrigin: {}\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
-        path, host, origin, key
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nOrigin: {}\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n{}\r\n",
+        path, host_header, origin, key, extensions
     );
 
-    let mut stream = TcpStream::connect((host, port)).await?;
+    let mut stream = tls::connect(host, port, tls).await?;
     stream.write_all(request.as_bytes()).await?;
 
     // Read header
     let mut buf = Vec::new();
-    let mut reader = io::BufReader::new(&stream);
+    let mut reader = io::BufReader::new(&mut stream);
     read_raw_header(&mut reader, &mut buf).await?;
 
     let mut headers = [httparse::EMPTY_HEADER; 64];
     let mut response = httparse::Response::new(&mut headers);
-    match response.parse(&buf)? {
-        httparse::Status::Partial => {
-            return Err(format!("Invalid header").into());
+    match response.parse(&buf) {
+        Ok(httparse::Status::Complete(_)) => (),
+        Ok(httparse::Status::Partial) | Err(_) => {
+            return Err(Error::Handshake("Invalid header".to_owned()));
         }
-        httparse::Status::Complete(_) => (),
     }
 
-    if response.code.unwrap_or(0) != 101 {
-        return Err(format!("Response != 101").into());
+    let status = response.code.unwrap_or(0);
+    if status != 101 {
+        let reason = format!("Expected status 101, got {}", status);
+        return Err(Error::Handshake(reason));
     }
 
-    // Verify `Sec-WebSocket-Accept`
-    for header in response.headers {
-        match header.name {
-            "Sec-WebSocket-Accept" => {
-                check_sec_websocket_accept(&key, header.value)?;
+    let header_value = |name: &str| {
+        response
+            .headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case(name))
+            .map(|header| header.value)
+    };
+    if !header_value("Upgrade").is_some_and(|v| v.eq_ignore_ascii_case(b"websocket")) {
+        return Err(Error::Handshake("Missing Upgrade: websocket".to_owned()));
+    }
+    let connection_upgrade = header_value("Connection").is_some_and(|v| {
+        v.split(|b| *b == b',')
+            .any(|token| token.trim_ascii().eq_ignore_ascii_case(b"upgrade"))
+    });
+    if !connection_upgrade {
+        return Err(Error::Handshake("Missing Connection: Upgrade".to_owned()));
+    }
+    match header_value("Sec-WebSocket-Accept") {
+        Some(value) => check_sec_websocket_accept(&key, value)?,
+        None => return Err(Error::Handshake("Missing Sec-WebSocket-Accept".to_owned())),
+    }
+
+    let deflate = match header_value("Sec-WebSocket-Extensions") {
+        Some(value) => {
+            let value = String::from_utf8_lossy(value);
+            match config.deflate {
+                Some(ref deflate) => Some(deflate.accept(&value)?),
+                None => return Err(Error::Handshake(format!("Unexpected extension: {}", value))),
             }
-            _ => (),
         }
-    }
+        None => None,
+    };
 
-    Ok(stream)
+    let leftover = reader.buffer().to_vec();
+    drop(reader);
+    Ok((stream, leftover, deflate))
 }
 
-fn check_sec_websocket_accept(key: &str, accept_value: &[u8]) -> Result<(), Error> {
+fn accept_key(key: &str) -> String {
     use sha1::{Digest, Sha1};
     const ACCEPT_SUFFIX: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
     let accept = format!("{}{}", key, ACCEPT_SUFFIX);
     let mut hasher = Sha1::new();
     hasher.update(accept.as_bytes());
-    let hashed = hasher.finalize();
-    let encoded = base64::encode(hashed.as_slice());
-    if accept_value == encoded.as_bytes() {
+    base64::encode(hasher.finalize())
+}
+
+fn check_sec_websocket_accept(key: &str, accept_value: &[u8]) -> Result<(), Error> {
+    if accept_value == accept_key(key).as_bytes() {
         Ok(())
     } else {
-        println!("{:?} != {:?}", accept_value, hashed.as_slice());
-        Err(format!("Invalid Sec-WebSocket-Accept: {:?}", accept_value).into())
+        Err(Error::Handshake(format!(
+            "Invalid Sec-WebSocket-Accept: {:?}",
+            accept_value
+        )))
     }
 }
 
-async fn receive_frame(reader: &mut io::BufReader<TcpStream>) -> Result<Frame, Error> {
-    let header = read_header(reader).await?;
+fn encode_close_payload(frame: Option<&CloseFrame>) -> Vec<u8> {
+    let mut payload = Vec::new();
+    if let Some(frame) = frame {
+        payload.extend_from_slice(&frame.code.to_be_bytes());
+        payload.extend_from_slice(frame.reason.as_bytes());
+    }
+    payload
+}
+
+/// Decodes the payload of a close frame. Errors come with the close code to
+/// fail the connection with.
+fn decode_close_payload(payload: &[u8]) -> Result<Option<CloseFrame>, (u16, Error)> {
+    match payload.len() {
+        0 => Ok(None),
+        1 => {
+            let err = Error::Protocol("Invalid close frame".to_owned());
+            Err((CLOSE_PROTOCOL_ERROR, err))
+        }
+        _ => {
+            let code = u16::from_be_bytes([payload[0], payload[1]]);
+            if !is_valid_close_code(code) {
+                let err = Error::Protocol(format!("Invalid close code: {}", code));
+                return Err((CLOSE_PROTOCOL_ERROR, err));
+            }
+            let reason = String::from_utf8(payload[2..].to_vec())
+                .map_err(|_| (CLOSE_INVALID_PAYLOAD, invalid_utf8()))?;
+            Ok(Some(CloseFrame { code, reason }))
+        }
+    }
+}
+
+/// Whether `code` may be sent in a close frame (RFC 6455 section 7.4).
+fn is_valid_close_code(code: u16) -> bool {
+    match code {
+        1000..=1003 | 1007..=1011 => true,
+        // Registered by IANA after RFC 6455.
+        1012..=1014 => true,
+        // Reserved for libraries, frameworks and applications.
+        3000..=4999 => true,
+        _ => false,
+    }
+}
+
+/// Decodes a frame at the beginning of `buf` and removes it from `buf`.
+/// Returns `None` if `buf` doesn't contain a complete frame yet.
+fn decode_frame(buf: &mut Vec<u8>) -> Result<Option<Frame>, Error> {
+    let (header, header_len) = match read_header(buf)? {
+        Some(header) => header,
+        None => return Ok(None),
+    };
     if header.mask {
-        return Err(format!("Frame should not be masked").into());
+        return Err(Error::Protocol("Frame should not be masked".to_owned()));
     }
 
-    let mut payload = vec![0; header.payload_len];
-    reader.read_exact(&mut payload).await?;
+    let frame_len = header_len + header.payload_len;
+    if buf.len() < frame_len {
+        return Ok(None);
+    }
+    let payload = buf[header_len..frame_len].to_vec();
+    buf.drain(..frame_len);
 
-    Ok(Frame { header, payload })
+    Ok(Some(Frame { header, payload }))
 }
 
-async fn send_text_frame(mut stream: TcpStream, text: String) -> Result<(), Error> {
-    let masking_key = rand::thread_rng().gen::<[u8; 4]>();
+/// Appends `data` to `buf` as a message, split into frames of at most
+/// `max_frame_size` bytes if given. `compressed` sets RSV1 on the first
+/// frame.
+fn encode_message(
+    buf: &mut Vec<u8>,
+    opcode: Opcode,
+    compressed: bool,
+    data: &[u8],
+    max_frame_size: Option<usize>,
+) {
+    let frame_size = match max_frame_size {
+        Some(size) if size > 0 && data.len() > size => size,
+        _ => return encode_frame(buf, opcode, true, compressed, data),
+    };
 
-    let mut payload = vec![0; text.len()];
-    let data = text.as_bytes();
-    for i in 0..text.len() {
-        payload[i] = data[i] ^ masking_key[i % 4];
+    let mut chunks = data.chunks(frame_size).peekable();
+    let mut opcode = opcode;
+    let mut rsv1 = compressed;
+    while let Some(chunk) = chunks.next() {
+        let fin = chunks.peek().is_none();
+        encode_frame(buf, opcode, fin, rsv1, chunk);
+        opcode = Opcode::ContinuationFrame;
+        rsv1 = false;
     }
+}
+
+/// Appends a masked frame to `buf`.
+fn encode_frame(buf: &mut Vec<u8>, opcode: Opcode, fin: bool, rsv1: bool, data: &[u8]) {
+    let masking_key = rand::thread_rng().gen::<[u8; 4]>();
 
     let header = FrameHeader {
-        fin: true,
-        opcode: Opcode::TextFrame,
+        fin,
+        rsv1,
+        opcode,
         mask: true,
-        payload_len: payload.len(),
+        payload_len: data.len(),
         masking_key: Some(masking_key),
     };
+    write_header(buf, &header);
 
-    write_header(&mut stream, &header).await?;
-    stream.write_all(&payload).await?;
-
-    Ok(())
+    buf.extend(data.iter().enumerate().map(|(i, b)| b ^ masking_key[i % 4]));
 }
 
-async fn write_header(stream: &mut TcpStream, header: &FrameHeader) -> Result<(), Error> {
+fn write_header(out: &mut Vec<u8>, header: &FrameHeader) {
     let mut buf = [0; 10];
-    buf[0] = ((header.fin as u8) << 7) | header.opcode as u8;
+    buf[0] = ((header.fin as u8) << 7) | ((header.rsv1 as u8) << 6) | header.opcode as u8;
     buf[1] = (header.mask as u8) << 7;
 
     let len = header.payload_len;
@@ -196,72 +832,869 @@ async fn write_header(stream: &mut TcpStream, header: &FrameHeader) -> Result<()
         buf[1] |= len as u8;
         2
     } else if len <= 65535 {
+        // Extended payload lengths are in network byte order.
         buf[1] |= 126;
-        buf[2] = (len & 0xff) as u8;
-        buf[3] = ((len >> 8) & 0xff) as u8;
+        buf[2..4].copy_from_slice(&(len as u16).to_be_bytes());
         4
     } else {
         buf[1] |= 127;
-        buf[2] = ((len >> 0) & 0xff) as u8;
-        buf[3] = ((len >> 8) & 0xff) as u8;
-        buf[4] = ((len >> 16) & 0xff) as u8;
-        buf[5] = ((len >> 24) & 0xff) as u8;
-        buf[6] = ((len >> 32) & 0xff) as u8;
-        buf[7] = ((len >> 40) & 0xff) as u8;
-        buf[8] = ((len >> 48) & 0xff) as u8;
-        buf[9] = ((len >> 56) & 0xff) as u8;
+        buf[2..10].copy_from_slice(&(len as u64).to_be_bytes());
         10
     };
 
-    stream.write_all(&buf[..size]).await?;
+    out.extend_from_slice(&buf[..size]);
     if let Some(masking_key) = header.masking_key.as_ref() {
-        stream.write_all(masking_key).await?;
+        out.extend_from_slice(masking_key);
     }
-
-    Ok(())
 }
 
-async fn read_header(reader: &mut io::BufReader<TcpStream>) -> Result<FrameHeader, Error> {
-    let mut first_two = [0; 2];
-    reader.read_exact(&mut first_two).await?;
-    let fin = first_two[0] & 0x80 == 0x80;
-    let opcode = Opcode::from_u8(first_two[0] & 0x0f)?;
-    let mask = first_two[1] & 0x80 == 0x80;
-    let payload_len = first_two[1] & 0x7f;
+/// Parses a frame header at the beginning of `buf`. Returns the header and
+/// its length in bytes, or `None` if `buf` is too short.
+fn read_header(buf: &[u8]) -> Result<Option<(FrameHeader, usize)>, Error> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let fin = buf[0] & 0x80 == 0x80;
+    // RSV1 is used by permessage-deflate, which the caller checks.
+    let rsv1 = buf[0] & 0x40 == 0x40;
+    if buf[0] & 0x30 != 0 {
+        return Err(Error::Protocol("Reserved bits must be zero".to_owned()));
+    }
+    let opcode = Opcode::from_u8(buf[0] & 0x0f)?;
+    let mask = buf[1] & 0x80 == 0x80;
+    let payload_len = buf[1] & 0x7f;
+
+    if opcode.is_control() {
+        if !fin {
+            return Err(Error::Protocol(
+                "Control frames must not be fragmented".to_owned(),
+            ));
+        }
+        if payload_len as usize > MAX_CONTROL_PAYLOAD_LEN {
+            return Err(Error::Protocol(
+                "Control frame payload too large".to_owned(),
+            ));
+        }
+    }
 
     // Deserialize payload length
-    let payload_len = if payload_len <= 125 {
-        payload_len as usize
+    let (payload_len, mut pos) = if payload_len <= 125 {
+        (payload_len as usize, 2)
     } else if payload_len == 126 {
-        let mut buf = [0; 2];
-        reader.read_exact(&mut buf).await?;
-        ((buf[0] as usize) << 8) | (buf[1] as usize)
+        if buf.len() < 4 {
+            return Ok(None);
+        }
+        (((buf[2] as usize) << 8) | (buf[3] as usize), 4)
     } else {
         // payload_len == 127
-        let mut buf = [0; 8];
-        reader.read_exact(&mut buf).await?;
-        ((buf[0] as usize) << 56)
-            | ((buf[1] as usize) << 48)
-            | ((buf[2] as usize) << 40)
-            | ((buf[3] as usize) << 32)
-            | ((buf[4] as usize) << 24)
-            | ((buf[5] as usize) << 16)
-            | ((buf[6] as usize) << 8)
-            | ((buf[7] as usize) << 0)
+        if buf.len() < 10 {
+            return Ok(None);
+        }
+        let mut len = [0; 8];
+        len.copy_from_slice(&buf[2..10]);
+        let len = u64::from_be_bytes(len);
+        if len >> 63 != 0 {
+            return Err(Error::Protocol(
+                "The most significant bit of payload length must be zero".to_owned(),
+            ));
+        }
+        if len > usize::MAX as u64 {
+            return Err(Error::Protocol("Payload too large".to_owned()));
+        }
+        (len as usize, 10)
     };
 
     let mut masking_key = None;
     if mask {
-        let mut buf = [0; 4];
-        reader.read_exact(&mut buf).await?;
-        masking_key = Some(buf);
+        if buf.len() < pos + 4 {
+            return Ok(None);
+        }
+        let mut key = [0; 4];
+        key.copy_from_slice(&buf[pos..pos + 4]);
+        masking_key = Some(key);
+        pos += 4;
     }
 
-    Ok(FrameHeader {
+    let header = FrameHeader {
         fin,
+        rsv1,
         opcode,
         mask,
         payload_len,
         masking_key,
-    })
+    };
+    Ok(Some((header, pos)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_net::{TcpListener, TcpStream};
+    use futures::{SinkExt, StreamExt};
+
+    /// The server side of a connection, used as an in-process peer.
+    struct Peer {
+        stream: TcpStream,
+        buf: Vec<u8>,
+    }
+
+    impl Peer {
+        /// Accepts a connection and completes the opening handshake.
+        /// `response_headers` are added to the handshake response and `extra` is written right
+        /// after it.
+        async fn accept(listener: &TcpListener, response_headers: &str, extra: &[u8]) -> Peer {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut buf = Vec::new();
+            let mut reader = io::BufReader::new(&stream);
+            read_raw_header(&mut reader, &mut buf).await.unwrap();
+            let mut headers = [httparse::EMPTY_HEADER; 64];
+            let mut request = httparse::Request::new(&mut headers);
+            request.parse(&buf).unwrap();
+            let key = request
+                .headers
+                .iter()
+                .find(|header| header.name == "Sec-WebSocket-Key")
+                .map(|header| std::str::from_utf8(header.value).unwrap())
+                .unwrap();
+
+            let mut response = format!(
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n{}\r\n",
+                accept_key(key),
+                response_headers
+            )
+            .into_bytes();
+            response.extend_from_slice(extra);
+            stream.write_all(&response).await.unwrap();
+
+            Peer {
+                stream,
+                buf: Vec::new(),
+            }
+        }
+
+        async fn send_raw(&mut self, data: &[u8]) {
+            self.stream.write_all(data).await.unwrap();
+        }
+
+        async fn send_frame(&mut self, opcode: Opcode, fin: bool, payload: &[u8]) {
+            self.send_raw(&unmasked_frame(opcode, fin, payload)).await;
+        }
+
+        /// Reads a frame sent by the client. Returns `None` on EOF.
+        async fn receive_frame(&mut self) -> Option<Frame> {
+            loop {
+                if let Some((header, header_len)) = read_header(&self.buf).unwrap() {
+                    let frame_len = header_len + header.payload_len;
+                    if self.buf.len() >= frame_len {
+                        let key = header.masking_key.expect("Client frames must be masked");
+                        let payload = self.buf[header_len..frame_len]
+                            .iter()
+                            .enumerate()
+                            .map(|(i, b)| b ^ key[i % 4])
+                            .collect();
+                        self.buf.drain(..frame_len);
+                        return Some(Frame { header, payload });
+                    }
+                }
+                let mut chunk = [0; READ_CHUNK_SIZE];
+                let n = self.stream.read(&mut chunk).await.unwrap();
+                if n == 0 {
+                    return None;
+                }
+                self.buf.extend_from_slice(&chunk[..n]);
+            }
+        }
+
+        async fn receive_close_code(&mut self) -> u16 {
+            let frame = self.receive_frame().await.unwrap();
+            assert!(matches!(frame.header.opcode, Opcode::Close));
+            decode_close_payload(&frame.payload).unwrap().unwrap().code
+        }
+    }
+
+    fn unmasked_frame(opcode: Opcode, fin: bool, payload: &[u8]) -> Vec<u8> {
+        unmasked_frame_with_rsv1(opcode, fin, false, payload)
+    }
+
+    fn unmasked_frame_with_rsv1(opcode: Opcode, fin: bool, rsv1: bool, payload: &[u8]) -> Vec<u8> {
+        let header = FrameHeader {
+            fin,
+            rsv1,
+            opcode,
+            mask: false,
+            payload_len: payload.len(),
+            masking_key: None,
+        };
+        let mut buf = Vec::new();
+        write_header(&mut buf, &header);
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    /// Runs `client` against `peer` over a loopback connection.
+    fn run_with_peer<P, PF, C, CF>(config: Config, extra: &[u8], peer: P, client: C)
+    where
+        P: FnOnce(Peer) -> PF + Send + 'static,
+        PF: Future<Output = ()> + Send + 'static,
+        C: FnOnce(Sender, Receiver) -> CF,
+        CF: Future<Output = ()>,
+    {
+        run_with_deflate_peer(config, None, extra, peer, client)
+    }
+
+    /// Like `run_with_peer`, but the peer accepts permessage-deflate with
+    /// the given response if any.
+    fn run_with_deflate_peer<P, PF, C, CF>(
+        config: Config,
+        extension: Option<&str>,
+        extra: &[u8],
+        peer: P,
+        client: C,
+    ) where
+        P: FnOnce(Peer) -> PF + Send + 'static,
+        PF: Future<Output = ()> + Send + 'static,
+        C: FnOnce(Sender, Receiver) -> CF,
+        CF: Future<Output = ()>,
+    {
+        let headers = match extension {
+            Some(extension) => format!("Sec-WebSocket-Extensions: {}\r\n", extension),
+            None => String::new(),
+        };
+        let extra = extra.to_vec();
+        smol::run(async move {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = smol::Task::spawn(async move {
+                let stream = Peer::accept(&listener, &headers, &extra).await;
+                peer(stream).await;
+            });
+
+            let url = Url::parse(&format!("ws://{}/devtools/page/1", addr)).unwrap();
+            let (sender, receiver) = connect(url, &config).await.unwrap();
+            client(sender, receiver).await;
+            server.await;
+        })
+    }
+
+    #[test]
+    fn header_round_trip() {
+        for &len in &[0, 1, 125, 126, 0x1234, 65535, 65536, 0x0123_4567] {
+            let header = FrameHeader {
+                fin: true,
+                rsv1: false,
+                opcode: Opcode::BinaryFrame,
+                mask: true,
+                payload_len: len,
+                masking_key: Some([1, 2, 3, 4]),
+            };
+            let mut buf = Vec::new();
+            write_header(&mut buf, &header);
+
+            let (decoded, header_len) = read_header(&buf).unwrap().unwrap();
+            assert_eq!(header_len, buf.len());
+            assert_eq!(decoded.payload_len, len);
+            assert_eq!(decoded.masking_key, Some([1, 2, 3, 4]));
+            assert!(decoded.fin);
+        }
+    }
+
+    #[test]
+    fn extended_lengths_are_big_endian() {
+        let mut buf = Vec::new();
+        write_header(&mut buf, &unmasked_header(0x1234));
+        assert_eq!(buf, [0x82, 126, 0x12, 0x34]);
+
+        let mut buf = Vec::new();
+        write_header(&mut buf, &unmasked_header(0x0001_0203));
+        assert_eq!(buf, [0x82, 127, 0, 0, 0, 0, 0x00, 0x01, 0x02, 0x03]);
+    }
+
+    fn unmasked_header(payload_len: usize) -> FrameHeader {
+        FrameHeader {
+            fin: true,
+            rsv1: false,
+            opcode: Opcode::BinaryFrame,
+            mask: false,
+            payload_len,
+            masking_key: None,
+        }
+    }
+
+    #[test]
+    fn incomplete_header() {
+        assert!(read_header(&[]).unwrap().is_none());
+        assert!(read_header(&[0x81]).unwrap().is_none());
+        assert!(read_header(&[0x81, 126, 0]).unwrap().is_none());
+        assert!(read_header(&[0x81, 127, 0, 0, 0, 0, 0, 0, 0])
+            .unwrap()
+            .is_none());
+        assert!(read_header(&[0x81, 0x85, 1, 2, 3]).unwrap().is_none());
+
+        let mut buf = vec![0x81, 3, b'a', b'b'];
+        assert!(decode_frame(&mut buf).unwrap().is_none());
+        assert_eq!(buf.len(), 4);
+    }
+
+    #[test]
+    fn invalid_headers() {
+        // Reserved bits. RSV1 is checked by the receiver.
+        assert!(read_header(&[0xc1, 0]).unwrap().unwrap().0.rsv1);
+        assert!(read_header(&[0xa1, 0]).is_err());
+        assert!(read_header(&[0x91, 0]).is_err());
+        // Reserved opcodes.
+        for opcode in (0x3..=0x7).chain(0xb..=0xf) {
+            assert!(read_header(&[0x80 | opcode, 0]).is_err());
+        }
+        // Control frames larger than 125 bytes.
+        assert!(read_header(&[0x89, 126, 0, 126]).is_err());
+        // Fragmented control frames.
+        assert!(read_header(&[0x09, 0]).is_err());
+        // The most significant bit of a 64-bit length.
+        assert!(read_header(&[0x82, 127, 0x80, 0, 0, 0, 0, 0, 0, 0]).is_err());
+        // Masked frames from the server.
+        let mut buf = vec![0x81, 0x80, 1, 2, 3, 4];
+        assert!(decode_frame(&mut buf).is_err());
+    }
+
+    #[test]
+    fn close_payload() {
+        let frame = CloseFrame {
+            code: CLOSE_NORMAL,
+            reason: "bye".to_owned(),
+        };
+        let payload = encode_close_payload(Some(&frame));
+        assert_eq!(payload, [0x03, 0xe8, b'b', b'y', b'e']);
+        assert_eq!(decode_close_payload(&payload).unwrap(), Some(frame));
+        assert_eq!(decode_close_payload(&[]).unwrap(), None);
+
+        assert!(decode_close_payload(&[0x03]).is_err());
+        for &code in &[0u16, 999, 1004, 1005, 1006, 1015, 2999, 5000] {
+            assert!(decode_close_payload(&code.to_be_bytes()).is_err());
+        }
+        assert!(decode_close_payload(&[0x03, 0xe8, 0xff]).is_err());
+    }
+
+    #[test]
+    fn echo() {
+        let messages = vec![
+            Message::Text("Hello".to_owned()),
+            Message::Text("a".repeat(200)),
+            Message::Text("b".repeat(70_000)),
+            Message::Binary((0..=255).collect()),
+        ];
+        let count = messages.len();
+        run_with_peer(
+            Config::default(),
+            b"",
+            move |mut peer| async move {
+                for _ in 0..count {
+                    let frame = peer.receive_frame().await.unwrap();
+                    assert!(frame.header.fin);
+                    peer.send_frame(frame.header.opcode, true, &frame.payload)
+                        .await;
+                }
+            },
+            |mut sender, mut receiver| async move {
+                for message in messages {
+                    sender.send(message.clone()).await.unwrap();
+                    assert_eq!(receiver.next().await.unwrap().unwrap(), message);
+                }
+            },
+        );
+    }
+
+    #[test]
+    fn send_control_messages() {
+        run_with_peer(
+            Config::default(),
+            b"",
+            |mut peer| async move {
+                let frame = peer.receive_frame().await.unwrap();
+                assert!(matches!(frame.header.opcode, Opcode::Ping));
+                assert_eq!(frame.payload, b"are you there");
+                let frame = peer.receive_frame().await.unwrap();
+                assert!(matches!(frame.header.opcode, Opcode::Pong));
+                assert_eq!(frame.payload, b"yes");
+            },
+            |mut sender, _receiver| async move {
+                sender
+                    .send(Message::Ping(b"are you there".to_vec()))
+                    .await
+                    .unwrap();
+                sender.send(Message::Pong(b"yes".to_vec())).await.unwrap();
+            },
+        );
+    }
+
+    #[test]
+    fn connection_lost() {
+        // Between messages, the stream just ends.
+        run_with_peer(
+            Config::default(),
+            b"",
+            |mut peer| async move {
+                peer.send_frame(Opcode::TextFrame, true, b"last").await;
+            },
+            |_sender, mut receiver| async move {
+                let message = receiver.next().await.unwrap().unwrap();
+                assert_eq!(message, Message::Text("last".to_owned()));
+                assert!(receiver.next().await.is_none());
+            },
+        );
+
+        // In the middle of a message, it fails.
+        run_with_peer(
+            Config::default(),
+            b"",
+            |mut peer| async move {
+                peer.send_frame(Opcode::TextFrame, false, b"unfinished")
+                    .await;
+            },
+            |_sender, mut receiver| async move {
+                match receiver.next().await {
+                    Some(Err(Error::Io(err))) => {
+                        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof)
+                    }
+                    res => panic!("unexpected {:?}", res),
+                }
+            },
+        );
+    }
+
+    #[test]
+    fn frames_right_after_handshake() {
+        let frame = unmasked_frame(Opcode::TextFrame, true, b"early");
+        run_with_peer(
+            Config::default(),
+            &frame,
+            |_peer| async move {},
+            |_sender, mut receiver| async move {
+                let message = receiver.next().await.unwrap().unwrap();
+                assert_eq!(message, Message::Text("early".to_owned()));
+            },
+        );
+    }
+
+    #[test]
+    fn fragmented_message_with_ping() {
+        run_with_peer(
+            Config::default(),
+            b"",
+            |mut peer| async move {
+                peer.send_frame(Opcode::TextFrame, false, b"Hel").await;
+                peer.send_frame(Opcode::Ping, true, b"ping").await;
+                // A code point split across fragments.
+                peer.send_frame(Opcode::ContinuationFrame, false, b"lo, \xc3")
+                    .await;
+                peer.send_frame(Opcode::ContinuationFrame, true, b"\xa9!")
+                    .await;
+
+                let frame = peer.receive_frame().await.unwrap();
+                assert!(matches!(frame.header.opcode, Opcode::Pong));
+                assert_eq!(frame.payload, b"ping");
+            },
+            |_sender, mut receiver| async move {
+                let message = receiver.next().await.unwrap().unwrap();
+                assert_eq!(message, Message::Text("Hello, \u{e9}!".to_owned()));
+            },
+        );
+    }
+
+    #[test]
+    fn send_fragmented() {
+        let config = Config {
+            max_frame_size: Some(4),
+            ..Config::default()
+        };
+        run_with_peer(
+            config,
+            b"",
+            |mut peer| async move {
+                let mut frames = Vec::new();
+                for _ in 0..3 {
+                    let frame = peer.receive_frame().await.unwrap();
+                    frames.push((frame.header.opcode as u8, frame.header.fin, frame.payload));
+                }
+                assert_eq!(
+                    frames,
+                    vec![
+                        (0x1, false, b"abcd".to_vec()),
+                        (0x0, false, b"efgh".to_vec()),
+                        (0x0, true, b"ij".to_vec()),
+                    ]
+                );
+            },
+            |mut sender, _receiver| async move {
+                let message = Message::Text("abcdefghij".to_owned());
+                sender.send(message).await.unwrap();
+            },
+        );
+    }
+
+    /// Sends `data` from the peer and checks that the client fails and
+    /// closes the connection with `code`.
+    fn expect_failure(config: Config, data: Vec<u8>, code: u16) {
+        run_with_peer(
+            config,
+            b"",
+            move |mut peer| async move {
+                peer.send_raw(&data).await;
+                assert_eq!(peer.receive_close_code().await, code);
+            },
+            |_sender, mut receiver| async move {
+                assert!(receiver.next().await.unwrap().is_err());
+                assert!(receiver.next().await.is_none());
+            },
+        );
+    }
+
+    #[test]
+    fn message_too_big() {
+        let config = Config {
+            max_message_size: Some(8),
+            ..Config::default()
+        };
+        let data = unmasked_frame(Opcode::TextFrame, true, b"123456789");
+        expect_failure(config.clone(), data, CLOSE_MESSAGE_TOO_BIG);
+
+        let mut data = unmasked_frame(Opcode::TextFrame, false, b"12345");
+        data.extend(unmasked_frame(Opcode::ContinuationFrame, true, b"6789"));
+        expect_failure(config, data, CLOSE_MESSAGE_TOO_BIG);
+    }
+
+    #[test]
+    fn reassemble_up_to_the_limit() {
+        let config = Config {
+            max_message_size: Some(8),
+            ..Config::default()
+        };
+        run_with_peer(
+            config,
+            b"",
+            |mut peer| async move {
+                peer.send_frame(Opcode::BinaryFrame, false, &[1, 2, 3])
+                    .await;
+                peer.send_frame(Opcode::ContinuationFrame, false, &[]).await;
+                peer.send_frame(Opcode::ContinuationFrame, true, &[4, 5, 6, 7, 8])
+                    .await;
+            },
+            |_sender, mut receiver| async move {
+                let message = receiver.next().await.unwrap().unwrap();
+                assert_eq!(message, Message::Binary(vec![1, 2, 3, 4, 5, 6, 7, 8]));
+            },
+        );
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        let data = unmasked_frame(Opcode::TextFrame, true, b"\xff");
+        expect_failure(Config::default(), data, CLOSE_INVALID_PAYLOAD);
+
+        // Detected before the message completes.
+        let data = unmasked_frame(Opcode::TextFrame, false, b"ok\xc3\x28");
+        expect_failure(Config::default(), data, CLOSE_INVALID_PAYLOAD);
+
+        let mut payload = CLOSE_NORMAL.to_be_bytes().to_vec();
+        payload.push(0xff);
+        let data = unmasked_frame(Opcode::Close, true, &payload);
+        expect_failure(Config::default(), data, CLOSE_INVALID_PAYLOAD);
+    }
+
+    #[test]
+    fn protocol_errors() {
+        let cases = vec![
+            // RSV1 without permessage-deflate.
+            vec![0xc1, 0],
+            // Reserved opcode.
+            vec![0x83, 0],
+            // Masked frame.
+            vec![0x81, 0x80, 1, 2, 3, 4],
+            // Continuation without a start.
+            unmasked_frame(Opcode::ContinuationFrame, true, b"x"),
+            // A new message while another is fragmented.
+            [
+                unmasked_frame(Opcode::TextFrame, false, b"x"),
+                unmasked_frame(Opcode::TextFrame, true, b"y"),
+            ]
+            .concat(),
+            // Oversized control frame.
+            unmasked_frame(Opcode::Ping, true, &[0; 126]),
+            // Fragmented control frame.
+            unmasked_frame(Opcode::Ping, false, b""),
+            // Invalid close code.
+            unmasked_frame(Opcode::Close, true, &1005u16.to_be_bytes()),
+        ];
+        for data in cases {
+            expect_failure(Config::default(), data, CLOSE_PROTOCOL_ERROR);
+        }
+    }
+
+    fn deflate_params() -> DeflateParams {
+        DeflateConfig::default()
+            .accept("permessage-deflate")
+            .unwrap()
+    }
+
+    #[test]
+    fn deflate_negotiation() {
+        let config = DeflateConfig {
+            server_no_context_takeover: true,
+            ..DeflateConfig::default()
+        };
+        assert_eq!(
+            config.offer(),
+            "permessage-deflate; server_no_context_takeover"
+        );
+        assert!(config.accept("permessage-deflate").is_err());
+        assert!(config
+            .accept("permessage-deflate; server_no_context_takeover; server_max_window_bits=10")
+            .is_ok());
+
+        let config = DeflateConfig::default();
+        assert!(config.accept("x-webkit-deflate-frame").is_err());
+        assert!(config.accept("permessage-deflate; foo").is_err());
+        assert!(config
+            .accept("permessage-deflate; server_max_window_bits=16")
+            .is_err());
+        assert!(config
+            .accept("permessage-deflate; client_no_context_takeover; client_no_context_takeover")
+            .is_err());
+    }
+
+    #[test]
+    fn deflate_round_trip() {
+        let config = DeflateConfig::default();
+        let mut compressor = Compressor::new(deflate_params(), &config);
+        let mut decompressor = Decompressor::new(deflate_params());
+        for _ in 0..3 {
+            let data = "Network.requestWillBeSent ".repeat(1000);
+            let compressed = compressor.compress(data.as_bytes()).unwrap();
+            assert!(compressed.len() < data.len() / 10);
+            let decompressed = decompressor.decompress(&compressed, None).unwrap();
+            assert_eq!(decompressed, data.as_bytes());
+        }
+
+        let data = vec![0; 1000];
+        let compressed = compressor.compress(&data).unwrap();
+        let decompressed = decompressor.decompress(&compressed, Some(10)).unwrap();
+        assert!(decompressed.len() > 10 && decompressed.len() < 1000);
+    }
+
+    #[test]
+    fn deflate_messages() {
+        let large = "a".repeat(200);
+        let expected = large.clone();
+        run_with_deflate_peer(
+            Config::default(),
+            Some("permessage-deflate"),
+            b"",
+            move |mut peer| async move {
+                let mut decompressor = Decompressor::new(deflate_params());
+                let frame = peer.receive_frame().await.unwrap();
+                assert!(frame.header.rsv1);
+                let payload = decompressor.decompress(&frame.payload, None).unwrap();
+                assert_eq!(payload, expected.as_bytes());
+
+                // Small messages are sent as is.
+                let frame = peer.receive_frame().await.unwrap();
+                assert!(!frame.header.rsv1);
+                assert_eq!(frame.payload, b"hi");
+
+                // A compressed message split into two fragments.
+                let config = DeflateConfig::default();
+                let mut compressor = Compressor::new(deflate_params(), &config);
+                let payload = compressor.compress(expected.as_bytes()).unwrap();
+                let (first, second) = payload.split_at(payload.len() / 2);
+                let mut data = unmasked_frame_with_rsv1(Opcode::TextFrame, false, true, first);
+                data.extend(unmasked_frame(Opcode::ContinuationFrame, true, second));
+                peer.send_raw(&data).await;
+            },
+            |mut sender, mut receiver| async move {
+                sender.send(Message::Text(large.clone())).await.unwrap();
+                sender.send(Message::Text("hi".to_owned())).await.unwrap();
+                let message = receiver.next().await.unwrap().unwrap();
+                assert_eq!(message, Message::Text(large));
+            },
+        );
+    }
+
+    #[test]
+    fn deflate_errors() {
+        let config = DeflateConfig::default();
+        let mut compressor = Compressor::new(deflate_params(), &config);
+        let bomb = compressor.compress(&[b'x'; 100]).unwrap();
+        let cases = vec![
+            // RSV1 on a continuation frame.
+            (
+                [
+                    unmasked_frame_with_rsv1(Opcode::TextFrame, false, true, b""),
+                    unmasked_frame_with_rsv1(Opcode::ContinuationFrame, true, true, b""),
+                ]
+                .concat(),
+                CLOSE_PROTOCOL_ERROR,
+            ),
+            // RSV1 on a control frame.
+            (
+                unmasked_frame_with_rsv1(Opcode::Ping, true, true, b""),
+                CLOSE_PROTOCOL_ERROR,
+            ),
+            // Corrupt compressed data.
+            (
+                unmasked_frame_with_rsv1(Opcode::TextFrame, true, true, &[0xff; 8]),
+                CLOSE_INVALID_PAYLOAD,
+            ),
+            // Too big once decompressed.
+            (
+                unmasked_frame_with_rsv1(Opcode::TextFrame, true, true, &bomb),
+                CLOSE_MESSAGE_TOO_BIG,
+            ),
+        ];
+        for (data, code) in cases {
+            let config = Config {
+                max_message_size: Some(50),
+                ..Config::default()
+            };
+            run_with_deflate_peer(
+                config,
+                Some("permessage-deflate"),
+                b"",
+                move |mut peer| async move {
+                    peer.send_raw(&data).await;
+                    assert_eq!(peer.receive_close_code().await, code);
+                },
+                |_sender, mut receiver| async move {
+                    assert!(receiver.next().await.unwrap().is_err());
+                    assert!(receiver.next().await.is_none());
+                },
+            );
+        }
+    }
+
+    #[test]
+    fn unsolicited_extension() {
+        smol::run(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = smol::Task::spawn(async move {
+                let headers = "Sec-WebSocket-Extensions: permessage-deflate\r\n";
+                Peer::accept(&listener, headers, b"").await;
+            });
+
+            let config = Config {
+                deflate: None,
+                ..Config::default()
+            };
+            let url = Url::parse(&format!("ws://{}/devtools/page/1", addr)).unwrap();
+            assert!(connect(url, &config).await.is_err());
+            server.await;
+        })
+    }
+
+    #[test]
+    fn close_from_peer() {
+        run_with_peer(
+            Config::default(),
+            b"",
+            |mut peer| async move {
+                let payload = encode_close_payload(Some(&CloseFrame {
+                    code: 1001,
+                    reason: "bye".to_owned(),
+                }));
+                peer.send_frame(Opcode::Close, true, &payload).await;
+                assert_eq!(peer.receive_close_code().await, 1001);
+            },
+            |mut sender, mut receiver| async move {
+                let message = receiver.next().await.unwrap().unwrap();
+                let frame = CloseFrame {
+                    code: 1001,
+                    reason: "bye".to_owned(),
+                };
+                assert_eq!(message, Message::Close(Some(frame)));
+                assert!(receiver.next().await.is_none());
+
+                let message = Message::Text("too late".to_owned());
+                assert!(sender.send(message).await.is_err());
+            },
+        );
+    }
+
+    #[test]
+    fn close_from_client() {
+        run_with_peer(
+            Config::default(),
+            b"",
+            |mut peer| async move {
+                assert_eq!(peer.receive_close_code().await, CLOSE_NORMAL);
+                let payload = encode_close_payload(Some(&CloseFrame {
+                    code: CLOSE_NORMAL,
+                    reason: String::new(),
+                }));
+                peer.send_frame(Opcode::Close, true, &payload).await;
+                // No echo for the reply.
+                assert!(peer.receive_frame().await.is_none());
+            },
+            |mut sender, mut receiver| async move {
+                sender.close().await.unwrap();
+                let message = receiver.next().await.unwrap().unwrap();
+                assert!(
+                    matches!(message, Message::Close(Some(ref frame)) if frame.code == CLOSE_NORMAL)
+                );
+                assert!(receiver.next().await.is_none());
+                drop(sender);
+                drop(receiver);
+            },
+        );
+    }
+
+    #[test]
+    fn pings_and_unsolicited_pongs() {
+        run_with_peer(
+            Config::default(),
+            b"",
+            |mut peer| async move {
+                peer.send_frame(Opcode::Pong, true, b"unsolicited").await;
+                peer.send_frame(Opcode::Ping, true, b"1").await;
+                peer.send_frame(Opcode::Ping, true, b"").await;
+                peer.send_frame(Opcode::TextFrame, true, b"after").await;
+                for payload in [&b"1"[..], b""].iter() {
+                    let frame = peer.receive_frame().await.unwrap();
+                    assert!(matches!(frame.header.opcode, Opcode::Pong));
+                    assert_eq!(&frame.payload[..], *payload);
+                }
+            },
+            |_sender, mut receiver| async move {
+                // Control frames are handled without surfacing.
+                let message = receiver.next().await.unwrap().unwrap();
+                assert_eq!(message, Message::Text("after".to_owned()));
+                assert!(receiver.next().await.is_none());
+            },
+        );
+    }
+
+    #[test]
+    fn keepalive() {
+        let config = Config {
+            keepalive_interval: Some(Duration::from_millis(20)),
+            ..Config::default()
+        };
+        run_with_peer(
+            config,
+            b"",
+            |mut peer| async move {
+                let frame = peer.receive_frame().await.unwrap();
+                assert!(matches!(frame.header.opcode, Opcode::Ping));
+                peer.send_frame(Opcode::Pong, true, &frame.payload).await;
+
+                // Don't answer the next one.
+                let frame = peer.receive_frame().await.unwrap();
+                assert!(matches!(frame.header.opcode, Opcode::Ping));
+                while peer.receive_frame().await.is_some() {}
+            },
+            |_sender, mut receiver| async move {
+                let err = receiver.next().await.unwrap().unwrap_err();
+                assert!(matches!(err, Error::Disconnected(_)));
+            },
+        );
+    }
 }
@@ -1,123 +1,1097 @@
-use smol::prelude::*;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use async_io::Timer;
+use futures::channel::{mpsc, oneshot};
+use futures::future::{self, Either, FutureExt};
+use futures::lock::Mutex as AsyncMutex;
+use futures::{Future, Sink, SinkExt, Stream, StreamExt};
 use url::Url;
 
+use crate::parser::{self, ParseError};
+#[cfg(any(feature = "async-std", feature = "smol", feature = "tokio"))]
+use crate::pipe;
+#[cfg(feature = "protocol")]
+use crate::protocol::{self, Command};
+#[cfg(any(feature = "async-std", feature = "smol", feature = "tokio"))]
+use crate::runtime;
+use crate::transport::Transport;
 use crate::Error;
 
-use crate::websocket;
+use crate::websocket::{self, CloseFrame, Message};
 
 #[derive(Debug)]
-pub(crate) struct MethodCall {
+pub struct MethodCall {
     domain: String,
     name: String,
     params: serde_json::Value,
 }
 
 impl MethodCall {
-    pub(crate) fn from_str(s: &str) -> Option<Self> {
-        parse_method_call(s)
+    /// Parses a call written as `Domain.method({...})`. The parentheses may
+    /// be left out if there are no parameters.
+    pub fn parse(s: &str) -> Result<Self, ParseError> {
+        MethodCall::parse_with_variables(s, &HashMap::new())
+    }
+
+    /// Like `parse`, but parameters may refer to `variables`, e.g.
+    /// `{nodeId: $doc.root.nodeId}`.
+    pub fn parse_with_variables(
+        s: &str,
+        variables: &HashMap<String, serde_json::Value>,
+    ) -> Result<Self, ParseError> {
+        let (method, params) = parser::parse_method_call(s, Some(variables))?;
+        Ok(MethodCall::new(&method, params))
+    }
+
+    /// Whether `s` is the start of a method call that has unclosed brackets,
+    /// so more lines could complete it.
+    pub fn is_incomplete(s: &str) -> bool {
+        match parser::parse_method_call(s, None) {
+            Ok(_) => false,
+            Err(err) => err.is_incomplete(),
+        }
+    }
+
+    /// Returns the keys in `args`, the start of a call's parameters object,
+    /// up to where it stops parsing. The keys may be unquoted, as in
+    /// `parse`.
+    pub fn param_keys(args: &str) -> Vec<String> {
+        parser::object_keys(args)
+    }
+
+    pub fn new(method: &str, params: serde_json::Value) -> Self {
+        let (domain, name) = match method.find('.') {
+            Some(dot) => (&method[..dot], &method[dot + 1..]),
+            None => (method, ""),
+        };
+        MethodCall {
+            domain: domain.to_owned(),
+            name: name.to_owned(),
+            params,
+        }
     }
 
-    pub(crate) fn serialize(&self, id: usize) -> String {
-        let msg = serde_json::json!({
+    /// Returns the method name, e.g. `Page.navigate`.
+    pub fn method(&self) -> String {
+        format!("{}.{}", self.domain, self.name)
+    }
+
+    pub fn params(&self) -> &serde_json::Value {
+        &self.params
+    }
+
+    /// Serializes the call, addressed to `session_id` if given.
+    pub(crate) fn serialize(&self, id: usize, session_id: Option<&str>) -> String {
+        let mut msg = serde_json::json!({
             "id": id,
-            "method": format!("{}.{}", self.domain, self.name),
+            "method": self.method(),
             "params": self.params,
         });
+        if let Some(session_id) = session_id {
+            msg["sessionId"] = session_id.into();
+        }
         msg.to_string()
     }
 }
 
-fn parse_method_call(line: &str) -> Option<MethodCall> {
-    // Tentative
-    let bytes = line.as_bytes();
-    let dot = match bytes.iter().position(|b| *b == b'.') {
-        Some(pos) => pos,
-        None => return None,
-    };
+/// Pending calls by id, with the session they were sent to.
+type PendingMap = HashMap<
+    usize,
+    (
+        Option<String>,
+        oneshot::Sender<Result<serde_json::Value, MethodError>>,
+    ),
+>;
 
-    let remaining = &bytes[dot..];
-    let lparen = match remaining.iter().position(|b| *b == b'(') {
-        Some(pos) => dot + pos,
-        None => return None,
-    };
+/// An error object returned by DevTools in reply to a method call.
+#[derive(Debug)]
+pub struct MethodError {
+    pub code: i64,
+    pub message: String,
+    pub data: Option<serde_json::Value>,
+}
 
-    let remaining = &bytes[lparen..];
-    let offset = bytes.len() - lparen;
-    let rparen = match remaining.iter().rev().position(|b| *b == b')') {
-        Some(pos) => lparen + (offset - pos) - 1,
-        None => return None,
-    };
+impl MethodError {
+    fn from_value(value: &serde_json::Value) -> Self {
+        let code = value.get("code").and_then(|v| v.as_i64()).unwrap_or(0);
+        let message = value
+            .get("message")
+            .and_then(|v| v.as_str())
+            .unwrap_or("")
+            .to_owned();
+        let data = value.get("data").cloned();
+        MethodError {
+            code,
+            message,
+            data,
+        }
+    }
+}
 
-    let domain = unsafe { String::from_utf8_unchecked(bytes[..dot].to_vec()) };
-    let name = unsafe { String::from_utf8_unchecked(bytes[dot + 1..lparen].to_vec()) };
+impl std::fmt::Display for MethodError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)?;
+        if let Some(ref data) = self.data {
+            write!(f, ": {}", data)?;
+        }
+        Ok(())
+    }
+}
 
-    let params_bytes = &bytes[lparen + 1..rparen];
-    let params = if params_bytes.len() == 0 {
-        serde_json::from_str("{}").unwrap()
-    } else {
-        match serde_json::from_slice(params_bytes) {
-            Ok(params) => params,
-            Err(_) => return None,
+impl std::error::Error for MethodError {}
+
+/// An event sent from DevTools.
+#[derive(Debug, Clone)]
+pub struct Event {
+    /// The session the event belongs to, if any.
+    pub session_id: Option<String>,
+    pub method: String,
+    pub params: serde_json::Value,
+}
+
+impl Event {
+    /// Decodes the parameters as `E`. Returns `None` if this is a different
+    /// event.
+    #[cfg(feature = "protocol")]
+    pub fn parse<E: protocol::Event>(&self) -> Option<Result<E, Error>> {
+        if self.method != E::NAME {
+            return None;
         }
-    };
+        Some(serde_json::from_value(self.params.clone()).map_err(Error::from))
+    }
+}
+
+/// Selects which events an `EventStream` receives.
+#[derive(Debug, Clone)]
+pub enum EventFilter {
+    /// Every event.
+    All,
+    /// Events of a domain, e.g. `Network`.
+    Domain(String),
+    /// A single event, e.g. `Network.requestWillBeSent`.
+    Method(String),
+}
+
+impl std::str::FromStr for EventFilter {
+    type Err = std::convert::Infallible;
+
+    /// Parses `*`, `Domain` or `Domain.event`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let filter = if s == "*" {
+            EventFilter::All
+        } else if s.contains('.') {
+            EventFilter::Method(s.to_owned())
+        } else {
+            EventFilter::Domain(s.to_owned())
+        };
+        Ok(filter)
+    }
+}
 
-    Some(MethodCall {
-        domain,
-        name,
-        params,
-    })
+impl EventFilter {
+    fn matches(&self, method: &str) -> bool {
+        match self {
+            EventFilter::All => true,
+            EventFilter::Domain(domain) => method
+                .strip_prefix(domain.as_str())
+                .is_some_and(|rest| rest.starts_with('.')),
+            EventFilter::Method(name) => name == method,
+        }
+    }
 }
 
-pub(crate) struct WebSocketTarget {
-    sender: websocket::Sender,
-    method_id: usize,
+/// Which sessions a subscriber receives events from.
+#[derive(Debug, Clone)]
+enum Scope {
+    /// Events that don't belong to a session.
+    Root,
+    /// Events of a single session.
+    Session(String),
+    /// Every event.
+    All,
+}
+
+impl Scope {
+    fn matches(&self, session_id: Option<&str>) -> bool {
+        match self {
+            Scope::Root => session_id.is_none(),
+            Scope::Session(id) => session_id == Some(id.as_str()),
+            Scope::All => true,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Subscribers {
+    next_id: usize,
+    entries: HashMap<usize, (Scope, EventFilter, mpsc::UnboundedSender<Event>)>,
+}
+
+impl Subscribers {
+    fn dispatch(&mut self, event: &Event) {
+        let session_id = event.session_id.as_deref();
+        // Drop subscribers whose stream has gone away.
+        self.entries.retain(|_, (scope, filter, tx)| {
+            !scope.matches(session_id)
+                || !filter.matches(&event.method)
+                || tx.unbounded_send(event.clone()).is_ok()
+        });
+    }
+}
+
+/// Why a connection was closed.
+#[derive(Debug, Clone)]
+pub enum Closed {
+    /// The close handshake completed. Holds the close frame sent by the
+    /// browser.
+    Handshake(Option<CloseFrame>),
+    /// The connection was lost without a close handshake.
+    Disconnected(String),
+}
+
+impl std::fmt::Display for Closed {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Closed::Handshake(Some(frame)) => write!(f, "Closed ({})", frame),
+            Closed::Handshake(None) => write!(f, "Closed"),
+            Closed::Disconnected(reason) => write!(f, "Disconnected: {}", reason),
+        }
+    }
+}
+
+/// States shared between a connection and its receive loop.
+struct Shared {
+    pending: Mutex<PendingMap>,
+    subscribers: Mutex<Subscribers>,
+    closed: Mutex<Option<oneshot::Sender<Closed>>>,
+}
+
+impl Shared {
+    /// Ends pending calls and event streams of a session that went away.
+    fn detach_session(&self, session_id: &str) {
+        self.pending
+            .lock()
+            .unwrap()
+            .retain(|_, (id, _)| id.as_deref() != Some(session_id));
+        self.subscribers
+            .lock()
+            .unwrap()
+            .entries
+            .retain(|_, (scope, _, _)| !matches!(scope, Scope::Session(id) if id == session_id));
+    }
+}
+
+/// A stream of events matching an `EventFilter`. Dropping the stream
+/// unsubscribes it.
+pub struct EventStream {
+    id: usize,
+    receiver: mpsc::UnboundedReceiver<Event>,
+    shared: Arc<Shared>,
+}
+
+impl Stream for EventStream {
+    type Item = Event;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Event>> {
+        Pin::new(&mut self.receiver).poll_next(cx)
+    }
+}
+
+impl Drop for EventStream {
+    fn drop(&mut self) {
+        self.shared
+            .subscribers
+            .lock()
+            .unwrap()
+            .entries
+            .remove(&self.id);
+    }
+}
+
+/// Removes a pending call when its future completes or is dropped, so that
+/// abandoned calls don't pile up.
+struct PendingGuard {
+    id: usize,
+    shared: Arc<Shared>,
+}
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        self.shared.pending.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// The sending half of the transport a connection runs over.
+type BoxSender = Pin<Box<dyn Sink<Message, Error = Error> + Send>>;
+
+/// The parts of a connection shared by a `WebSocketTarget` and its sessions.
+#[derive(Clone)]
+struct Connection {
+    sender: Arc<AsyncMutex<BoxSender>>,
+    method_id: Arc<AtomicUsize>,
+    shared: Arc<Shared>,
+    default_timeout: Option<Duration>,
+}
+
+impl Connection {
+    fn call_method(
+        &self,
+        session_id: Option<&str>,
+        method: &MethodCall,
+        timeout: Option<Duration>,
+    ) -> impl Future<Output = Result<serde_json::Value, Error>> {
+        let id = self.method_id.fetch_add(1, Ordering::Relaxed);
+        let msg = method.serialize(id, session_id);
+        let name = method.method();
+
+        let session_id = session_id.map(str::to_owned);
+        let shared = self.shared.clone();
+        let sender = self.sender.clone();
+        // Nothing is registered or sent until the future is polled, so an
+        // unused call leaves no trace.
+        async move {
+            let (tx, rx) = oneshot::channel();
+            shared.pending.lock().unwrap().insert(id, (session_id, tx));
+            let _guard = PendingGuard { id, shared };
+            let call = async {
+                sender.lock().await.send(Message::Text(msg)).await?;
+                match rx.await {
+                    Ok(Ok(result)) => Ok(result),
+                    Ok(Err(err)) => Err(err.into()),
+                    Err(oneshot::Canceled) => Err(Error::Disconnected(
+                        "Connection or session closed".to_owned(),
+                    )),
+                }
+            };
+            futures::pin_mut!(call);
+            match timeout {
+                Some(timeout) => match future::select(call, Timer::new(timeout)).await {
+                    Either::Left((result, _)) => result,
+                    Either::Right(_) => Err(Error::Timeout {
+                        method: name,
+                        timeout,
+                    }),
+                },
+                None => call.await,
+            }
+        }
+    }
+
+    #[cfg(feature = "protocol")]
+    fn call<C: Command>(
+        &self,
+        session_id: Option<&str>,
+        command: &C,
+    ) -> impl Future<Output = Result<C::Returns, Error>> {
+        let call = serde_json::to_value(command).map(|params| {
+            let method = MethodCall::new(C::NAME, params);
+            self.call_method(session_id, &method, self.default_timeout)
+        });
+        async move {
+            let result = call?.await?;
+            Ok(serde_json::from_value(result)?)
+        }
+    }
+
+    fn subscribe(&self, scope: Scope, filter: EventFilter) -> EventStream {
+        let (tx, rx) = mpsc::unbounded();
+        let mut subscribers = self.shared.subscribers.lock().unwrap();
+        let id = subscribers.next_id;
+        subscribers.next_id += 1;
+        subscribers.entries.insert(id, (scope, filter, tx));
+        EventStream {
+            id,
+            receiver: rx,
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+/// The receive loop of a connection. It reads messages from the transport,
+/// completes calls and delivers events until the connection is closed.
+///
+/// The crate doesn't depend on an async runtime: spawn the driver on the one
+/// in use, or poll it alongside the calls. With the `tokio`, `async-std` or
+/// `smol` feature, constructors like `WebSocketTarget::connect` spawn it.
+#[must_use = "a connection receives nothing unless its driver is polled"]
+pub struct Driver {
+    future: Pin<Box<dyn Future<Output = Result<(), Error>> + Send>>,
+}
+
+impl Future for Driver {
+    type Output = Result<(), Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.future.as_mut().poll(cx)
+    }
+}
+
+/// A WebSocket connection to a page or to the browser. A browser-level
+/// connection can attach to other targets and drive them as `Session`s over
+/// the same socket.
+///
+/// The same messages can also go over the pipes of a browser started with
+/// `--remote-debugging-pipe`, or any other `Transport`; see `from_pipe` and
+/// `new`.
+pub struct WebSocketTarget {
+    connection: Connection,
+    closed: future::Shared<oneshot::Receiver<Closed>>,
 }
 
 impl WebSocketTarget {
-    pub(crate) async fn connect(url: Url) -> Result<Self, Error> {
-        let method_id = 0;
-        let (sender, receiver) = websocket::connect(url).await?;
+    /// Connects to `url` and spawns the connection's `Driver` on the runtime
+    /// enabled by a cargo feature.
+    #[cfg(any(feature = "async-std", feature = "smol", feature = "tokio"))]
+    pub async fn connect(url: Url, config: &websocket::Config) -> Result<Self, Error> {
+        let (target, driver) = WebSocketTarget::connect_with_driver(url, config).await?;
+        runtime::spawn(driver);
+        Ok(target)
+    }
 
-        // Tentative; remove runtime (smol) dependency
-        smol::Task::spawn(receive_frames(receiver)).detach();
+    /// Connects to `url`. The caller must drive the returned `Driver`.
+    pub async fn connect_with_driver(
+        url: Url,
+        config: &websocket::Config,
+    ) -> Result<(Self, Driver), Error> {
+        let (sender, receiver) = websocket::connect(url, config).await?;
+        Ok(WebSocketTarget::new((sender, receiver)))
+    }
+
+    /// Talks to the browser over pipes, e.g. those of a browser launched
+    /// with `LaunchOptions::pipe`. The connection is browser-level.
+    #[cfg(any(feature = "async-std", feature = "smol", feature = "tokio"))]
+    pub fn from_pipe(sender: pipe::Sender, receiver: pipe::Receiver) -> Self {
+        WebSocketTarget::from_transport((sender, receiver))
+    }
 
-        Ok(WebSocketTarget { sender, method_id })
+    /// Runs the connection over `transport`, e.g. an in-memory `channel` or
+    /// a `Replayer` in tests, and spawns its `Driver`.
+    #[cfg(any(feature = "async-std", feature = "smol", feature = "tokio"))]
+    pub fn from_transport<T: Transport>(transport: T) -> Self {
+        let (target, driver) = WebSocketTarget::new(transport);
+        runtime::spawn(driver);
+        target
     }
 
-    pub(crate) fn call_method(
-        &mut self,
+    /// Runs the connection over `transport`. Nothing is received, so no
+    /// call completes, until the returned `Driver` is polled.
+    pub fn new<T: Transport>(transport: T) -> (Self, Driver) {
+        let (sender, receiver) = transport.split();
+        let (closed_tx, closed_rx) = oneshot::channel();
+        let shared = Arc::new(Shared {
+            pending: Mutex::new(HashMap::new()),
+            subscribers: Mutex::new(Subscribers::default()),
+            closed: Mutex::new(Some(closed_tx)),
+        });
+
+        let driver = Driver {
+            future: Box::pin(receive_messages(receiver, shared.clone())),
+        };
+        let target = WebSocketTarget {
+            connection: Connection {
+                sender: Arc::new(AsyncMutex::new(Box::pin(sender))),
+                method_id: Arc::new(AtomicUsize::new(0)),
+                shared,
+                default_timeout: None,
+            },
+            closed: closed_rx.shared(),
+        };
+        (target, driver)
+    }
+
+    /// Returns a future that resolves when the connection is closed.
+    pub fn closed(&self) -> impl Future<Output = Closed> {
+        let closed = self.closed.clone();
+        async move {
+            closed
+                .await
+                .unwrap_or_else(|_| Closed::Disconnected("Receive loop stopped".to_owned()))
+        }
+    }
+
+    /// Starts the close handshake and waits until the browser replies.
+    pub fn close(&self) -> impl Future<Output = Result<Closed, Error>> {
+        let sender = self.connection.sender.clone();
+        let closed = self.closed();
+        async move {
+            sender.lock().await.close().await?;
+            Ok(closed.await)
+        }
+    }
+
+    /// Sets the timeout for calls made without one. Sessions attached
+    /// afterwards inherit it.
+    pub fn set_default_timeout(&mut self, timeout: Option<Duration>) {
+        self.connection.default_timeout = timeout;
+    }
+
+    /// Sends `method` and returns a future that resolves to the `result`
+    /// object of the reply, or to a `MethodError` if DevTools replied with
+    /// an `error` object. Fails with `Error::Timeout` if the default timeout
+    /// passes first. Dropping the future cancels the call.
+    pub fn call_method(
+        &self,
+        method: &MethodCall,
+    ) -> impl Future<Output = Result<serde_json::Value, Error>> {
+        let timeout = self.connection.default_timeout;
+        self.connection.call_method(None, method, timeout)
+    }
+
+    /// Like `call_method`, but with its own timeout. `None` waits forever.
+    pub fn call_method_with_timeout(
+        &self,
         method: &MethodCall,
-    ) -> impl Future<Output = Result<(), Error>> {
-        let msg = method.serialize(self.method_id);
-        self.method_id += 1;
-        self.sender.send_text_frame(msg)
+        timeout: Option<Duration>,
+    ) -> impl Future<Output = Result<serde_json::Value, Error>> {
+        self.connection.call_method(None, method, timeout)
+    }
+
+    /// Sends a typed command and decodes its reply.
+    #[cfg(feature = "protocol")]
+    pub fn call<C: Command>(&self, command: &C) -> impl Future<Output = Result<C::Returns, Error>> {
+        self.connection.call(None, command)
+    }
+
+    /// Subscribes to events matching `filter` that don't belong to a
+    /// session. The stream ends when the connection is closed.
+    pub fn subscribe(&self, filter: EventFilter) -> EventStream {
+        self.connection.subscribe(Scope::Root, filter)
+    }
+
+    /// Subscribes to events matching `filter` from the connection and all of
+    /// its sessions.
+    pub fn subscribe_all(&self, filter: EventFilter) -> EventStream {
+        self.connection.subscribe(Scope::All, filter)
+    }
+
+    /// Attaches to `target_id` in flatten mode and returns its session.
+    pub fn attach(
+        &self,
+        target_id: impl AsRef<str>,
+    ) -> impl Future<Output = Result<Session, Error>> {
+        let params = serde_json::json!({
+            "targetId": target_id.as_ref(),
+            "flatten": true,
+        });
+        let method = MethodCall::new("Target.attachToTarget", params);
+        let result = self.call_method(&method);
+        let connection = self.connection.clone();
+        async move {
+            let result = result.await?;
+            let session_id = result
+                .get("sessionId")
+                .and_then(|id| id.as_str())
+                .ok_or_else(|| Error::InvalidResponse("No sessionId in reply".to_owned()))?;
+            Ok(Session {
+                id: session_id.to_owned(),
+                connection,
+            })
+        }
+    }
+
+    /// Returns a handle for a session attached by other means, e.g. with
+    /// `Target.setAutoAttach`.
+    pub fn session(&self, session_id: impl Into<String>) -> Session {
+        Session {
+            id: session_id.into(),
+            connection: self.connection.clone(),
+        }
     }
 }
 
-async fn receive_frames(mut receiver: websocket::Receiver) -> Result<(), Error> {
-    use colored_json::prelude::*;
+/// A target attached through a browser-level connection. Messages are
+/// routed by `sessionId`. Pending calls and event streams end when the
+/// session is detached.
+#[derive(Clone)]
+pub struct Session {
+    id: String,
+    connection: Connection,
+}
+
+impl Session {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Sets the timeout for calls made without one.
+    pub fn set_default_timeout(&mut self, timeout: Option<Duration>) {
+        self.connection.default_timeout = timeout;
+    }
+
+    /// Sends `method` to the session's target. See
+    /// `WebSocketTarget::call_method`.
+    pub fn call_method(
+        &self,
+        method: &MethodCall,
+    ) -> impl Future<Output = Result<serde_json::Value, Error>> {
+        let timeout = self.connection.default_timeout;
+        self.connection.call_method(Some(&self.id), method, timeout)
+    }
+
+    /// Like `call_method`, but with its own timeout. `None` waits forever.
+    pub fn call_method_with_timeout(
+        &self,
+        method: &MethodCall,
+        timeout: Option<Duration>,
+    ) -> impl Future<Output = Result<serde_json::Value, Error>> {
+        self.connection.call_method(Some(&self.id), method, timeout)
+    }
+
+    /// Sends a typed command to the session's target and decodes its reply.
+    #[cfg(feature = "protocol")]
+    pub fn call<C: Command>(&self, command: &C) -> impl Future<Output = Result<C::Returns, Error>> {
+        self.connection.call(Some(&self.id), command)
+    }
 
-    // Tentative: Open a file to log events.
-    use async_std::fs::File;
-    let mut events = File::create("events.log").await?;
+    /// Subscribes to events of this session matching `filter`.
+    pub fn subscribe(&self, filter: EventFilter) -> EventStream {
+        self.connection
+            .subscribe(Scope::Session(self.id.clone()), filter)
+    }
 
-    // TODO: Make receiver implement Stream.
-    loop {
-        let frame = receiver.receive_frame().await?;
-        assert!(frame.header.fin, "Fragmented frames aren't supported.");
+    /// Detaches from the target.
+    pub fn detach(&self) -> impl Future<Output = Result<(), Error>> {
+        let params = serde_json::json!({ "sessionId": self.id });
+        let method = MethodCall::new("Target.detachFromTarget", params);
+        let timeout = self.connection.default_timeout;
+        let result = self.connection.call_method(None, &method, timeout);
+        async move {
+            result.await?;
+            Ok(())
+        }
+    }
+}
 
-        let value: serde_json::Value = serde_json::from_slice(&frame.payload)?;
-        if let Some(ref _msg_id) = value.get("id") {
+async fn receive_messages<R>(receiver: R, shared: Arc<Shared>) -> Result<(), Error>
+where
+    R: Stream<Item = Result<Message, Error>> + Unpin,
+{
+    let res = dispatch_messages(receiver, &shared).await;
+    let closed = match res {
+        Ok(Some(ref frame)) => Closed::Handshake(frame.clone()),
+        Ok(None) => Closed::Disconnected("Connection reset".to_owned()),
+        Err(ref err) => Closed::Disconnected(err.to_string()),
+    };
+    // Closing the senders ends pending calls and event streams.
+    shared.pending.lock().unwrap().clear();
+    shared.subscribers.lock().unwrap().entries.clear();
+    if let Some(tx) = shared.closed.lock().unwrap().take() {
+        let _ = tx.send(closed);
+    }
+    res.map(|_| ())
+}
+
+/// Dispatches incoming messages until the connection is closed. Returns the
+/// close frame sent by the browser, or `None` if the connection was lost
+/// without a close handshake.
+async fn dispatch_messages<R>(
+    mut receiver: R,
+    shared: &Shared,
+) -> Result<Option<Option<CloseFrame>>, Error>
+where
+    R: Stream<Item = Result<Message, Error>> + Unpin,
+{
+    while let Some(message) = receiver.next().await {
+        let value = match message? {
+            Message::Text(text) => serde_json::from_str(&text),
+            Message::Binary(data) => serde_json::from_slice(&data),
+            Message::Close(frame) => return Ok(Some(frame)),
+            Message::Ping(_) | Message::Pong(_) => continue,
+        };
+        // One bad message shouldn't take down the connection.
+        let mut value: serde_json::Value = match value {
+            Ok(value) => value,
+            Err(err) => {
+                log::warn!("Ignoring a message that isn't valid JSON: {}", err);
+                continue;
+            }
+        };
+        if let Some(msg_id) = value.get("id") {
             // This is a reply for a method call.
-            let res = serde_json::to_string_pretty(&value)?;
-            println!("{}", res.to_colored_json_auto()?);
-        } else {
+            let msg_id = match msg_id.as_u64() {
+                Some(msg_id) => msg_id as usize,
+                None => continue,
+            };
+            let tx = match shared.pending.lock().unwrap().remove(&msg_id) {
+                Some((_, tx)) => tx,
+                None => continue,
+            };
+            let reply = match value.get("error") {
+                Some(error) => Err(MethodError::from_value(error)),
+                None => Ok(value.get("result").cloned().unwrap_or_default()),
+            };
+            // The caller may have gone away; nothing to do then.
+            let _ = tx.send(reply);
+        } else if let Some(method) = value.get("method").and_then(|m| m.as_str()) {
             // This is an event coming from DevTools.
-            let res = serde_json::to_string_pretty(&value)?;
-            let res = res.to_colored_json_auto()?;
-            events.write_all(res.as_bytes()).await?;
-            events.write_all(b"\n").await?;
-            events.sync_all().await?;
+            let event = Event {
+                session_id: value
+                    .get("sessionId")
+                    .and_then(|id| id.as_str())
+                    .map(str::to_owned),
+                method: method.to_owned(),
+                params: value
+                    .get_mut("params")
+                    .map(serde_json::Value::take)
+                    .unwrap_or_default(),
+            };
+            shared.subscribers.lock().unwrap().dispatch(&event);
+            if event.method == "Target.detachedFromTarget" {
+                if let Some(session_id) = event.params.get("sessionId").and_then(|id| id.as_str()) {
+                    shared.detach_session(session_id);
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{self, ChannelReceiver, ChannelSender, Transport};
+    use serde_json::json;
+
+    /// The browser's end of a connection, played by a test.
+    struct FakeBrowser {
+        sender: ChannelSender,
+        receiver: ChannelReceiver,
+    }
+
+    impl FakeBrowser {
+        async fn receive(&mut self) -> serde_json::Value {
+            match self.receiver.next().await {
+                Some(Ok(Message::Text(text))) => serde_json::from_str(&text).unwrap(),
+                message => panic!("unexpected {:?}", message),
+            }
+        }
+
+        async fn send(&mut self, value: serde_json::Value) {
+            let message = Message::Text(value.to_string());
+            self.sender.send(message).await.unwrap();
         }
+
+        /// Receives a call and replies with `result`.
+        async fn reply(&mut self, result: serde_json::Value) -> serde_json::Value {
+            let call = self.receive().await;
+            self.send(json!({"id": call["id"], "result": result})).await;
+            call
+        }
+    }
+
+    /// Connects a target to a fake browser. Must be called inside
+    /// `smol::run`.
+    fn connect() -> (WebSocketTarget, FakeBrowser) {
+        let (client, browser) = transport::channel();
+        let (target, driver) = WebSocketTarget::new(client);
+        smol::Task::spawn(driver).detach();
+        let (sender, receiver) = browser.split();
+        (target, FakeBrowser { sender, receiver })
+    }
+
+    fn pending_calls(target: &WebSocketTarget) -> usize {
+        target.connection.shared.pending.lock().unwrap().len()
+    }
+
+    #[test]
+    fn call_timeout() {
+        smol::run(async {
+            let (target, mut browser) = connect();
+            let method = MethodCall::new("Page.enable", json!({}));
+            let timeout = Duration::from_millis(50);
+            let call = target.call_method_with_timeout(&method, Some(timeout));
+            let (result, sent) = future::join(call, browser.receive()).await;
+            match result {
+                Err(Error::Timeout {
+                    method,
+                    timeout: after,
+                }) => {
+                    assert_eq!(method, "Page.enable");
+                    assert_eq!(after, timeout);
+                }
+                res => panic!("unexpected {:?}", res),
+            }
+            assert_eq!(pending_calls(&target), 0);
+
+            // A late reply is ignored.
+            browser.send(json!({"id": sent["id"], "result": {}})).await;
+            let method = MethodCall::new("Page.disable", json!({}));
+            let call = target.call_method(&method);
+            let (result, _) = future::join(call, browser.reply(json!({"ok": true}))).await;
+            assert_eq!(result.unwrap(), json!({"ok": true}));
+        });
+    }
+
+    #[test]
+    fn dropped_call() {
+        smol::run(async {
+            let (target, mut browser) = connect();
+            let method = MethodCall::new("Runtime.evaluate", json!({"expression": "1"}));
+
+            // A call that is never polled isn't registered or sent.
+            drop(target.call_method(&method));
+            assert_eq!(pending_calls(&target), 0);
+
+            let mut call = Box::pin(target.call_method(&method));
+            assert!(smol::future::poll_once(&mut call).await.is_none());
+            let sent = browser.receive().await;
+            assert_eq!(sent["method"], "Runtime.evaluate");
+            assert_eq!(pending_calls(&target), 1);
+            drop(call);
+            assert_eq!(pending_calls(&target), 0);
+
+            // The reply to the dropped call goes nowhere.
+            browser.send(json!({"id": sent["id"], "result": {}})).await;
+            let method = MethodCall::new("Page.enable", json!({}));
+            let call = target.call_method(&method);
+            let (result, _) = future::join(call, browser.reply(json!({}))).await;
+            assert_eq!(result.unwrap(), json!({}));
+            assert_eq!(pending_calls(&target), 0);
+        });
+    }
+
+    #[test]
+    fn session_routing() {
+        smol::run(async {
+            let (target, mut browser) = connect();
+            let attach = target.attach("T1");
+            let (session, sent) =
+                future::join(attach, browser.reply(json!({"sessionId": "S1"}))).await;
+            let session = session.unwrap();
+            assert_eq!(sent["method"], "Target.attachToTarget");
+            assert_eq!(sent["params"], json!({"targetId": "T1", "flatten": true}));
+            assert!(sent.get("sessionId").is_none());
+            assert_eq!(session.id(), "S1");
+
+            // Replies reach the session's call and the browser's call by id,
+            // whatever the order.
+            let method = MethodCall::new("Page.enable", json!({}));
+            let session_call = session.call_method(&method);
+            let browser_call = target.call_method(&method);
+            let fake = async {
+                let first = browser.receive().await;
+                let second = browser.receive().await;
+                let (to_session, to_browser) = if first.get("sessionId").is_some() {
+                    (first, second)
+                } else {
+                    (second, first)
+                };
+                assert_eq!(to_session["sessionId"], "S1");
+                assert!(to_browser.get("sessionId").is_none());
+                browser
+                    .send(json!({"id": to_browser["id"], "result": {"from": "browser"}}))
+                    .await;
+                browser
+                    .send(json!({"id": to_session["id"], "sessionId": "S1", "result": {"from": "S1"}}))
+                    .await;
+            };
+            let (session_result, browser_result, _) =
+                future::join3(session_call, browser_call, fake).await;
+            assert_eq!(session_result.unwrap(), json!({"from": "S1"}));
+            assert_eq!(browser_result.unwrap(), json!({"from": "browser"}));
+        });
+    }
+
+    #[test]
+    fn session_events() {
+        smol::run(async {
+            let (target, mut browser) = connect();
+            let root_events = target.subscribe(EventFilter::All);
+            let all_events = target.subscribe_all(EventFilter::All);
+            let session_events = target.session("S1").subscribe(EventFilter::All);
+
+            browser
+                .send(json!({"method": "Target.targetCreated", "params": {}}))
+                .await;
+            browser
+                .send(json!({"method": "Page.loadEventFired", "sessionId": "S1", "params": {}}))
+                .await;
+            browser
+                .send(json!({"method": "Page.loadEventFired", "sessionId": "S2", "params": {}}))
+                .await;
+            // The browser closing the connection ends the streams.
+            browser.sender.close().await.unwrap();
+
+            let sessions = |events: Vec<Event>| -> Vec<Option<String>> {
+                events.into_iter().map(|event| event.session_id).collect()
+            };
+            assert_eq!(sessions(root_events.collect().await), vec![None]);
+            assert_eq!(
+                sessions(session_events.collect().await),
+                vec![Some("S1".to_owned())]
+            );
+            assert_eq!(
+                sessions(all_events.collect().await),
+                vec![None, Some("S1".to_owned()), Some("S2".to_owned())]
+            );
+        });
+    }
+
+    #[test]
+    fn detached_session() {
+        smol::run(async {
+            let (target, mut browser) = connect();
+            let session = target.session("S1");
+            let mut session_events = session.subscribe(EventFilter::All);
+            let mut root_events = target.subscribe(EventFilter::All);
+
+            let method = MethodCall::new("Runtime.evaluate", json!({"expression": "1"}));
+            let call = session.call_method(&method);
+            let fake = async {
+                browser.receive().await;
+                browser
+                    .send(json!({
+                        "method": "Target.detachedFromTarget",
+                        "params": {"sessionId": "S1", "targetId": "T1"},
+                    }))
+                    .await;
+            };
+            let (result, _) = future::join(call, fake).await;
+            match result {
+                Err(Error::Disconnected(_)) => (),
+                res => panic!("unexpected {:?}", res),
+            }
+            assert!(session_events.next().await.is_none());
+
+            // The rest of the connection keeps working.
+            let event = root_events.next().await.unwrap();
+            assert_eq!(event.method, "Target.detachedFromTarget");
+            let method = MethodCall::new("Target.getTargets", json!({}));
+            let call = target.call_method(&method);
+            let (result, _) = future::join(call, browser.reply(json!({"targetInfos": []}))).await;
+            assert_eq!(result.unwrap(), json!({"targetInfos": []}));
+        });
+    }
+
+    #[test]
+    fn replies_by_id() {
+        smol::run(async {
+            let (target, mut browser) = connect();
+            let first = MethodCall::new("Runtime.evaluate", json!({"expression": "1"}));
+            let second = MethodCall::new("Runtime.evaluate", json!({"expression": "2"}));
+            let fake = async {
+                let first = browser.receive().await;
+                let second = browser.receive().await;
+                assert_ne!(first["id"], second["id"]);
+                assert_eq!(first["method"], "Runtime.evaluate");
+                assert_eq!(first["params"], json!({"expression": "1"}));
+                // Reply out of order, with a stray reply in between.
+                browser
+                    .send(json!({"id": second["id"], "result": {"value": 2}}))
+                    .await;
+                browser.send(json!({"id": 1000, "result": {}})).await;
+                browser
+                    .send(json!({"id": first["id"], "result": {"value": 1}}))
+                    .await;
+            };
+            let (first, second, _) = future::join3(
+                target.call_method(&first),
+                target.call_method(&second),
+                fake,
+            )
+            .await;
+            assert_eq!(first.unwrap(), json!({"value": 1}));
+            assert_eq!(second.unwrap(), json!({"value": 2}));
+        });
+    }
+
+    #[test]
+    fn error_reply() {
+        smol::run(async {
+            let (target, mut browser) = connect();
+            let method = MethodCall::new("Page.navigate", json!({}));
+            let fake = async {
+                let call = browser.receive().await;
+                browser
+                    .send(json!({
+                        "id": call["id"],
+                        "error": {"code": -32602, "message": "Invalid parameters", "data": "url"},
+                    }))
+                    .await;
+            };
+            let (result, _) = future::join(target.call_method(&method), fake).await;
+            match result {
+                Err(Error::Cdp(err)) => {
+                    assert_eq!(err.code, -32602);
+                    assert_eq!(err.message, "Invalid parameters");
+                    assert_eq!(err.data, Some(json!("url")));
+                    assert_eq!(err.to_string(), "Invalid parameters (-32602): \"url\"");
+                }
+                res => panic!("unexpected {:?}", res),
+            }
+            assert_eq!(pending_calls(&target), 0);
+        });
+    }
+
+    #[test]
+    fn event_filters() {
+        let filter = |s: &str| s.parse::<EventFilter>().unwrap();
+        assert!(filter("*").matches("Network.requestWillBeSent"));
+        assert!(filter("Network").matches("Network.requestWillBeSent"));
+        assert!(!filter("Network").matches("NetworkX.requestWillBeSent"));
+        assert!(!filter("Network").matches("Network"));
+        assert!(filter("Page.loadEventFired").matches("Page.loadEventFired"));
+        assert!(!filter("Page.loadEventFired").matches("Page.frameNavigated"));
+    }
+
+    #[test]
+    fn subscribers() {
+        smol::run(async {
+            let (target, mut browser) = connect();
+            let subscribers = || {
+                target
+                    .connection
+                    .shared
+                    .subscribers
+                    .lock()
+                    .unwrap()
+                    .entries
+                    .len()
+            };
+            let mut first = target.subscribe(EventFilter::Method("Page.loadEventFired".to_owned()));
+            let mut second = target.subscribe(EventFilter::Domain("Page".to_owned()));
+            let dropped = target.subscribe(EventFilter::All);
+            assert_eq!(subscribers(), 3);
+            drop(dropped);
+            assert_eq!(subscribers(), 2);
+
+            browser
+                .send(json!({"method": "Page.frameNavigated", "params": {"frame": {}}}))
+                .await;
+            browser
+                .send(json!({"method": "Page.loadEventFired", "params": {"timestamp": 1.5}}))
+                .await;
+            let event = first.next().await.unwrap();
+            assert_eq!(event.method, "Page.loadEventFired");
+            assert_eq!(event.params, json!({"timestamp": 1.5}));
+            assert_eq!(second.next().await.unwrap().method, "Page.frameNavigated");
+            assert_eq!(second.next().await.unwrap().method, "Page.loadEventFired");
+            drop(first);
+            drop(second);
+            assert_eq!(subscribers(), 0);
+        });
+    }
+
+    #[test]
+    fn invalid_messages() {
+        smol::run(async {
+            let (target, mut browser) = connect();
+            let method = MethodCall::new("Page.enable", json!({}));
+            let fake = async {
+                let call = browser.receive().await;
+                let garbage = Message::Text("{not json".to_owned());
+                browser.sender.send(garbage).await.unwrap();
+                browser.send(json!(["not", "an", "object"])).await;
+                browser.send(json!({"id": "x", "result": {}})).await;
+                browser.send(json!({"id": call["id"], "result": {}})).await;
+            };
+            let (result, _) = future::join(target.call_method(&method), fake).await;
+            assert_eq!(result.unwrap(), json!({}));
+        });
     }
 }